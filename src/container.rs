@@ -0,0 +1,180 @@
+/*
+    The cgroup of a containerized process tells its runtime, container and pod, examples of the layouts recognized:
+    /docker/3f4e...a1                                                                               (docker, cgroupfs driver)
+    /system.slice/docker-3f4e...a1.scope                                                            (docker, systemd driver)
+    /kubepods/burstable/pod0f1c2d3e-4b5a-6978-8a9b-0c1d2e3f4a5b/3f4e...a1                            (kubelet, cgroupfs driver)
+    /kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod0f1c2d3e_4b5a_6978_8a9b_0c1d2e3f4a5b.slice/cri-containerd-3f4e...a1.scope
+    /kubepods.slice/kubepods-pod0f1c....slice/crio-3f4e...a1.scope                                   (cri-o)
+    /machine.slice/libpod-3f4e...a1.scope                                                           (podman)
+*/
+
+const CONTAINER_ID_LENGTH: usize = 64;
+
+// the prefixes of the container scopes/directories and the runtimes they belong to
+const RUNTIME_PREFIXES: &[(&str, &str)] = &[
+    ("docker", "docker"),
+    ("cri-containerd", "containerd"),
+    ("nerdctl", "containerd"),
+    ("crio", "cri-o"),
+    ("libpod", "podman"),
+];
+
+// the parent directories of the bare container ids (cgroupfs driver)
+const RUNTIME_PARENTS: &[(&str, &str)] = &[("docker", "docker"), ("libpod_parent", "podman")];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Container {
+    // unknown for the bare ids of the kubelet cgroupfs layout
+    pub runtime: Option<&'static str>,
+    pub id: String,
+    pub pod_uid: Option<String>,
+}
+
+fn is_container_id(id: &str) -> bool {
+    id.len() == CONTAINER_ID_LENGTH && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+// e.g. docker-3f4e...a1.scope, crio-3f4e...a1 or 3f4e...a1
+fn parse_container_component(component: &str) -> Option<(Option<&'static str>, String)> {
+    let name = component.trim_end_matches(".scope");
+
+    if is_container_id(name) {
+        return Some((None, name.to_string()));
+    }
+
+    let (prefix, id) = name.rsplit_once('-')?;
+    if !is_container_id(id) {
+        return None;
+    }
+
+    // the conmon scope of cri-o (crio-conmon-<id>) is not the container
+    RUNTIME_PREFIXES
+        .iter()
+        .find(|(p, _)| *p == prefix)
+        .map(|(_, runtime)| (Some(*runtime), id.to_string()))
+}
+
+// e.g. pod0f1c2d3e-4b5a-6978-8a9b-0c1d2e3f4a5b or kubepods-besteffort-pod0f1c2d3e_4b5a_6978_8a9b_0c1d2e3f4a5b.slice
+fn parse_pod_component(component: &str) -> Option<String> {
+    let name = component.trim_end_matches(".slice");
+    let uid = match name.rsplit_once("-pod") {
+        Some((_, uid)) => uid,
+        None => name.strip_prefix("pod")?,
+    };
+
+    let uid = uid.replace('_', "-");
+    match uid.len() == 36 && uid.bytes().all(|b| b == b'-' || b.is_ascii_hexdigit()) {
+        true => Some(uid),
+        false => None,
+    }
+}
+
+/// The container a cgroup path belongs to, if any
+pub fn parse_cgroup_path(path: &str) -> Option<Container> {
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+
+    // the innermost container when they are nested (e.g. docker in docker)
+    let (position, (runtime, id)) = components
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(i, c)| parse_container_component(c).map(|parsed| (i, parsed)))?;
+
+    let runtime = runtime.or_else(|| {
+        let parent = components[..position].last()?;
+        RUNTIME_PARENTS
+            .iter()
+            .find(|(p, _)| p == parent)
+            .map(|(_, runtime)| *runtime)
+    });
+
+    Some(Container {
+        runtime,
+        id,
+        pod_uid: components[..position]
+            .iter()
+            .rev()
+            .find_map(|c| parse_pod_component(c)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "3f4e5d6c7b8a99887766554433221100ffeeddccbbaa00112233445566778899";
+    const INNER_ID: &str = "a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f90";
+    const POD_UID: &str = "0f1c2d3e-4b5a-6978-8a9b-0c1d2e3f4a5b";
+
+    // the cgroup paths of the supported layouts, with the container each one is expected to belong to
+    fn layouts() -> Vec<(&'static str, String, Option<Container>)> {
+        let container = |runtime, id: &str, pod_uid: Option<&str>| {
+            Some(Container {
+                runtime,
+                id: id.to_string(),
+                pod_uid: pod_uid.map(str::to_string),
+            })
+        };
+
+        vec![
+            (
+                "docker cgroupfs",
+                format!("/docker/{}", ID),
+                container(Some("docker"), ID, None),
+            ),
+            (
+                "docker systemd",
+                format!("/system.slice/docker-{}.scope", ID),
+                container(Some("docker"), ID, None),
+            ),
+            (
+                "kubelet cgroupfs",
+                format!("/kubepods/burstable/pod{}/{}", POD_UID, ID),
+                container(None, ID, Some(POD_UID)),
+            ),
+            (
+                "cri-containerd scope",
+                format!(
+                    "/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod{}.slice/cri-containerd-{}.scope",
+                    POD_UID.replace('-', "_"),
+                    ID
+                ),
+                container(Some("containerd"), ID, Some(POD_UID)),
+            ),
+            (
+                "crio scope",
+                format!(
+                    "/kubepods.slice/kubepods-pod{}.slice/crio-{}.scope",
+                    POD_UID.replace('-', "_"),
+                    ID
+                ),
+                container(Some("cri-o"), ID, Some(POD_UID)),
+            ),
+            (
+                "crio-conmon rejected",
+                format!(
+                    "/kubepods.slice/kubepods-pod{}.slice/crio-conmon-{}.scope",
+                    POD_UID.replace('-', "_"),
+                    ID
+                ),
+                None,
+            ),
+            (
+                "nested libpod",
+                format!(
+                    "/machine.slice/libpod-{}.scope/container/libpod_parent/libpod-{}.scope",
+                    ID, INNER_ID
+                ),
+                container(Some("podman"), INNER_ID, None),
+            ),
+            ("not a container", "/user.slice/user-1000.slice/session-2.scope".to_string(), None),
+        ]
+    }
+
+    #[test]
+    fn cgroup_path_layouts() {
+        for (name, path, expected) in layouts() {
+            assert_eq!(parse_cgroup_path(&path), expected, "layout {}", name);
+        }
+    }
+}
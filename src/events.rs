@@ -0,0 +1,410 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+
+use serde_json::json;
+
+use crate::container;
+use crate::incident;
+use crate::parser;
+use crate::process;
+use crate::psi;
+use crate::rules;
+use crate::userspace;
+
+/// Milliseconds since epoch, the time of the events
+pub fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+fn get_hostname() -> String {
+    match env::var("HOSTNAME") {
+        Ok(val) => val,
+        Err(_) => match fs::read_to_string("/proc/sys/kernel/hostname") {
+            Ok(host_name) => host_name.trim().to_string(),
+            Err(e) => {
+                error!(
+                    "Could not read /proc/sys/kernel/hostname to obtain the hostname: {}",
+                    e
+                );
+
+                "N/A".to_string()
+            }
+        },
+    }
+}
+
+fn get_kernel_version() -> String {
+    match fs::read_to_string("/proc/version") {
+        Ok(kernel_version) => kernel_version.trim().to_string(),
+        Err(e) => {
+            error!(
+                "Could not read /proc/version to obtain the kernel version: {}",
+                e
+            );
+
+            "N/A".to_string()
+        }
+    }
+}
+
+pub fn build_oom_candidates(
+    incident: &incident::Incident,
+    limit: usize,
+    cmdline: impl Fn(i32) -> Option<String>,
+) -> Vec<serde_json::Value> {
+    let mut tasks: Vec<&parser::TaskEntry> = incident
+        .tasks
+        .iter()
+        .filter(|t| t.pid != incident.victim.pid)
+        .collect();
+
+    // the kernel badness is mostly driven by the resident and swapped out memory of the task
+    tasks
+        .sort_by_key(|t| std::cmp::Reverse(t.rss_pages.unwrap_or(0) + t.swap_entries.unwrap_or(0)));
+
+    tasks
+        .into_iter()
+        .take(limit)
+        .map(|t| {
+            json!({ "pid": t.pid.to_string(),
+                    "name": t.name,
+                    "cmdline": cmdline(t.pid),
+                    "uid": t.uid,
+                    "total_vm_pages": t.total_vm_pages,
+                    "rss_pages": t.rss_pages,
+                    "pgtables_bytes": t.pgtables_bytes,
+                    "swap_entries": t.swap_entries,
+                    "oom_score_adj": t.oom_score_adj })
+        })
+        .collect()
+}
+
+fn build_trigger(
+    incident: &incident::Incident,
+    trigger_cmdline: Option<String>,
+) -> Option<serde_json::Value> {
+    incident.trigger.as_ref().map(|t| {
+        json!({ "pid": t.pid.map(|pid| pid.to_string()),
+                "comm": t.invocation.comm,
+                "cmdline": trigger_cmdline,
+                "gfp_mask": t.invocation.gfp_mask,
+                "order": t.invocation.order,
+                "oom_score_adj": t.invocation.oom_score_adj })
+    })
+}
+
+/// The snapshot of the killed process taken while it was alive
+pub fn build_process(process: &process::ProcessInfo) -> serde_json::Value {
+    json!({ "cmdline": process.cmdline,
+            "exe": process.exe,
+            "cwd": process.cwd,
+            "uid": process.uid,
+            "user": process.user,
+            "ppid": process.ppid.to_string(),
+            "start_time": process.start_time.map(|t| t.to_string()),
+            "comm": process.comm,
+            "cgroup": process.cgroup })
+}
+
+// the container of the process when it was snapshot, otherwise the one of the cgroup logged with the kill
+fn get_container(
+    process: Option<&process::ProcessInfo>,
+    cgroup: Option<&String>,
+) -> Option<container::Container> {
+    process
+        .and_then(|p| p.container.clone())
+        .or_else(|| cgroup.and_then(|c| container::parse_cgroup_path(c)))
+}
+
+pub fn build_ancestors(
+    ancestors: Vec<(i32, Option<process::ProcessInfo>)>,
+) -> Vec<serde_json::Value> {
+    ancestors
+        .into_iter()
+        .map(|(pid, ancestor)| {
+            json!({ "pid": pid.to_string(),
+                    "comm": ancestor.as_ref().map(|a| a.comm.clone()),
+                    "cmdline": ancestor.as_ref().map(|a| a.cmdline.clone()) })
+        })
+        .collect()
+}
+
+pub fn build_oom_event(
+    incident: &incident::Incident,
+    process: Option<process::ProcessInfo>,
+    ancestors: Vec<serde_json::Value>,
+    trigger_cmdline: Option<String>,
+    candidates: Vec<serde_json::Value>,
+) -> serde_json::Value {
+    let killed = &incident.victim;
+    let constraint = incident.constraint.as_ref();
+    let container = get_container(
+        process.as_ref(),
+        constraint.and_then(|c| c.task_memcg.as_ref()),
+    );
+
+    json!({ "event": "oom_kill",
+                    "killer": "kernel",
+                    "cmdline": process.as_ref().map(|p| p.cmdline.clone()),
+                    "process": process.as_ref().map(build_process),
+                    "ancestors": ancestors,
+                    "container_runtime": container.as_ref().and_then(|c| c.runtime),
+                    "container_id": container.as_ref().map(|c| c.id.clone()),
+                    "pod_uid": container.as_ref().and_then(|c| c.pod_uid.clone()),
+                    "pid": killed.pid.to_string(),
+                    "scope": incident.scope().as_str(),
+                    "comm": killed.comm,
+                    "total_vm_kb": killed.total_vm_kb,
+                    "anon_rss_kb": killed.anon_rss_kb,
+                    "file_rss_kb": killed.file_rss_kb,
+                    "shmem_rss_kb": killed.shmem_rss_kb,
+                    "uid": killed.uid,
+                    "pgtables_kb": killed.pgtables_kb,
+                    "oom_score_adj": killed.oom_score_adj,
+                    "constraint": constraint.map(|c| c.constraint.clone()),
+                    "cpuset": constraint.and_then(|c| c.cpuset.clone()),
+                    "oom_memcg": constraint.and_then(|c| c.oom_memcg.clone()),
+                    "task_memcg": constraint.and_then(|c| c.task_memcg.clone()),
+                    "trigger": build_trigger(incident, trigger_cmdline),
+                    "candidates": candidates,
+                    "mem_info_pages": incident.mem_info,
+                    "hostname": get_hostname(),
+                    "kernel": get_kernel_version(),
+                    "time": now_millis().to_string()})
+}
+
+/// Same shape as the kernel kills, the fields a userspace killer does not log are null.
+/// The process is the oldest victim (the main process of a killed cgroup), all of them are listed in victims.
+pub fn build_userspace_kill_event(
+    kill: &userspace::UserspaceKill,
+    victims: Vec<(i32, process::ProcessInfo)>,
+    ancestors: Vec<serde_json::Value>,
+) -> serde_json::Value {
+    let process = victims.first().map(|(_, process)| process);
+    let container = get_container(process, kill.cgroup.as_ref());
+
+    json!({ "event": "oom_kill",
+                    "killer": kill.killer,
+                    "cmdline": process.map(|p| p.cmdline.clone()),
+                    "process": process.map(build_process),
+                    "ancestors": ancestors,
+                    "container_runtime": container.as_ref().and_then(|c| c.runtime),
+                    "container_id": container.as_ref().map(|c| c.id.clone()),
+                    "pod_uid": container.as_ref().and_then(|c| c.pod_uid.clone()),
+                    "pid": kill.pid.map(|pid| pid.to_string()),
+                    "scope": kill.scope().as_str(),
+                    "comm": kill.comm,
+                    "total_vm_kb": null,
+                    "anon_rss_kb": null,
+                    "file_rss_kb": null,
+                    "shmem_rss_kb": null,
+                    "rss_kb": kill.rss_kb,
+                    "uid": kill.uid,
+                    "pgtables_kb": null,
+                    "oom_score_adj": null,
+                    "oom_score": kill.score,
+                    "signal": kill.signal,
+                    "reason": kill.reason,
+                    "constraint": null,
+                    "cpuset": null,
+                    "oom_memcg": null,
+                    "task_memcg": kill.cgroup,
+                    "trigger": null,
+                    "candidates": [],
+                    "victims": victims
+                        .iter()
+                        .map(|(pid, victim)| json!({ "pid": pid.to_string(),
+                                                      "comm": victim.comm,
+                                                      "cmdline": victim.cmdline }))
+                        .collect::<Vec<_>>(),
+                    "mem_info_pages": null,
+                    "hostname": get_hostname(),
+                    "kernel": get_kernel_version(),
+                    "time": now_millis().to_string()})
+}
+
+pub fn build_reaped_event(reaping: &incident::Reaping) -> serde_json::Value {
+    let reaped = &reaping.reaped;
+    let victim = reaping.victim.as_ref();
+    let reclaimed = |before: Option<u64>, after: Option<u64>| match (before, after) {
+        (Some(before), Some(after)) => Some(before.saturating_sub(after)),
+        _ => None,
+    };
+    let reclaimed_anon_kb = reclaimed(victim.and_then(|v| v.anon_rss_kb), reaped.anon_rss_kb);
+    let reclaimed_file_kb = reclaimed(victim.and_then(|v| v.file_rss_kb), reaped.file_rss_kb);
+    let reclaimed_shmem_kb = reclaimed(victim.and_then(|v| v.shmem_rss_kb), reaped.shmem_rss_kb);
+
+    json!({ "event": "oom_reaped",
+            "pid": reaped.pid.to_string(),
+            "comm": reaped.comm,
+            "scope": reaping.scope.map(|s| s.as_str()),
+            "anon_rss_kb": reaped.anon_rss_kb,
+            "file_rss_kb": reaped.file_rss_kb,
+            "shmem_rss_kb": reaped.shmem_rss_kb,
+            "reclaimed_anon_kb": reclaimed_anon_kb,
+            "reclaimed_file_kb": reclaimed_file_kb,
+            "reclaimed_shmem_kb": reclaimed_shmem_kb,
+            "reclaimed_kb": reclaimed_anon_kb.zip(reclaimed_file_kb).zip(reclaimed_shmem_kb).map(|((a, f), s)| a + f + s),
+            "reaping_ms": reaping.reaping_time.map(|t| t.as_millis() as u64),
+            "hostname": get_hostname(),
+            "kernel": get_kernel_version(),
+            "time": now_millis().to_string()})
+}
+
+pub fn get_panic_on_oom() -> Option<u32> {
+    match fs::read_to_string("/proc/sys/vm/panic_on_oom") {
+        Ok(content) => content.trim().parse::<u32>().ok(),
+        Err(e) => {
+            error!("Could not read /proc/sys/vm/panic_on_oom: {}", e);
+            None
+        }
+    }
+}
+
+pub fn build_failure_event(
+    incident: &incident::Incident,
+    failure: parser::OomFailure,
+    message: &str,
+    trigger_cmdline: Option<String>,
+) -> serde_json::Value {
+    json!({ "event": match failure {
+                parser::OomFailure::NoKillableProcesses => "oom_no_killable_processes",
+                parser::OomFailure::Panic => "oom_panic",
+            },
+            "severity": "critical",
+            "scope": incident.scope().as_str(),
+            "message": message,
+            "panic_on_oom": get_panic_on_oom(),
+            "trigger": build_trigger(incident, trigger_cmdline),
+            "mem_info_pages": incident.mem_info,
+            "hostname": get_hostname(),
+            "kernel": get_kernel_version(),
+            "time": now_millis().to_string()})
+}
+
+/// A report that panics the host unless the oom is constrained, which its header does not tell
+pub fn build_possible_panic_event(
+    incident: &incident::Incident,
+    message: &str,
+    trigger_cmdline: Option<String>,
+) -> serde_json::Value {
+    let mut event = build_failure_event(
+        incident,
+        parser::OomFailure::Panic,
+        message,
+        trigger_cmdline,
+    );
+    event["event"] = json!("oom_possible_panic");
+    event["severity"] = json!("warning");
+    event
+}
+
+pub fn build_rule_event(
+    rule_match: &rules::RuleMatch,
+    message: &str,
+    cmdline: Option<String>,
+) -> serde_json::Value {
+    json!({ "event": "kernel_rule",
+            "rule": rule_match.rule,
+            "pid": rule_match.pid.map(|pid| pid.to_string()),
+            "comm": rule_match.comm,
+            "cmdline": cmdline,
+            "fields": rule_match.fields,
+            "message": message,
+            "hostname": get_hostname(),
+            "kernel": get_kernel_version(),
+            "time": now_millis().to_string()})
+}
+
+pub fn build_dropped_event(first_sequence: u64, last_sequence: u64) -> serde_json::Value {
+    json!({ "event": "kernel_log_dropped",
+            "severity": "warning",
+            "message": "kernel log records dropped, an OOM may have been missed",
+            "first_sequence": first_sequence,
+            "last_sequence": last_sequence,
+            "dropped_records": last_sequence + 1 - first_sequence,
+            "hostname": get_hostname(),
+            "kernel": get_kernel_version(),
+            "time": now_millis().to_string()})
+}
+
+pub fn build_missed_kills_event(
+    expected: u64,
+    detected: u64,
+    handled: u64,
+    missed: u64,
+) -> serde_json::Value {
+    json!({ "event": "oom_kills_missed",
+            "severity": "warning",
+            "message": "the kernel counted more oom kills than the ones found in the kernel log",
+            "vmstat_oom_kills": expected,
+            "detected_oom_kills": detected,
+            "handled_oom_kills": handled,
+            "missed_oom_kills": missed,
+            "hostname": get_hostname(),
+            "kernel": get_kernel_version(),
+            "time": now_millis().to_string()})
+}
+
+pub fn build_consumer(
+    pid: i32,
+    comm: &str,
+    rss_pages: i64,
+    cmdline: Option<&String>,
+) -> serde_json::Value {
+    json!({ "pid": pid.to_string(),
+            "name": comm,
+            "cmdline": cmdline,
+            "rss_pages": rss_pages })
+}
+
+pub fn build_pressure_event(
+    path: &str,
+    cgroup: Option<&str>,
+    pressure: &BTreeMap<String, f64>,
+    exceeded: &[&psi::Threshold],
+    consumers: Vec<serde_json::Value>,
+) -> serde_json::Value {
+    json!({ "event": "memory_pressure",
+            "severity": "warning",
+            "message": "memory pressure high, an OOM may follow",
+            "scope": match cgroup {
+                Some(_) => incident::OomScope::Memcg.as_str(),
+                None => incident::OomScope::Global.as_str(),
+            },
+            "source": path,
+            "cgroup": cgroup,
+            "pressure": pressure,
+            "thresholds": exceeded
+                .iter()
+                .map(|t| json!({ "metric": t.metric, "threshold": t.value, "value": pressure.get(&t.metric) }))
+                .collect::<Vec<serde_json::Value>>(),
+            "top_consumers": consumers,
+            "hostname": get_hostname(),
+            "kernel": get_kernel_version(),
+            "time": now_millis().to_string()})
+}
+
+pub fn build_cgroup_oom_event(
+    counter: &str,
+    count: u64,
+    source: &str,
+    cgroup: &str,
+    memory_events: &BTreeMap<String, u64>,
+    victim: Option<serde_json::Value>,
+) -> serde_json::Value {
+    json!({ "event": format!("cgroup_{}", counter),
+            "scope": incident::OomScope::Memcg.as_str(),
+            "source": source,
+            "cgroup": cgroup,
+            "count": count,
+            "memory_events": memory_events,
+            "likely_victim": victim,
+            "hostname": get_hostname(),
+            "kernel": get_kernel_version(),
+            "time": now_millis().to_string()})
+}
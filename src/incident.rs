@@ -0,0 +1,622 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time;
+
+use crate::parser;
+
+/*
+    A kernel oom report spans many log lines, they are printed in this order:
+    stress invoked oom-killer: gfp_mask=0x100cca(GFP_HIGHUSER_MOVABLE), order=0, oom_score_adj=0
+    CPU: 1 PID: 4242 Comm: stress Not tainted 5.10.0-8-amd64 #1 Debian 5.10.46-4
+    ... stack trace ...
+    Mem-Info:
+    active_anon:2008 inactive_anon:981349 isolated_anon:0
+    ... per node and per zone statistics ...
+    Tasks state (memory values in pages):
+    [  pid  ]   uid  tgid total_vm      rss pgtables_bytes swapents oom_score_adj name
+    ... one line for every eligible task ...
+    oom-kill:constraint=CONSTRAINT_NONE,nodemask=(null),cpuset=/,mems_allowed=0,global_oom,task_memcg=/,task=stress,pid=4242,uid=0
+    Out of memory: Killed process 4242 (stress) total-vm:4198476kB, anon-rss:3924640kB, ...
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum Stage {
+    #[default]
+    Idle,
+    Header,
+    MemInfo,
+    TaskTable,
+    Summary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OomScope {
+    Global,
+    Memcg,
+    Cpuset,
+    Mempolicy,
+}
+
+impl OomScope {
+    pub const ALL: &'static [&'static str] = &["global", "memcg", "cpuset", "mempolicy"];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OomScope::Global => "global",
+            OomScope::Memcg => "memcg",
+            OomScope::Cpuset => "cpuset",
+            OomScope::Mempolicy => "mempolicy",
+        }
+    }
+
+    /// Parse a comma separated list of scopes, e.g. global,memcg
+    pub fn parse_list(scopes: &str) -> Result<Vec<String>, String> {
+        scopes
+            .split(',')
+            .map(|s| s.trim())
+            .map(|s| match OomScope::ALL.contains(&s) {
+                true => Ok(s.to_string()),
+                false => Err(format!(
+                    "Unknown scope '{}', the scopes are {}",
+                    s,
+                    OomScope::ALL.join("/")
+                )),
+            })
+            .collect()
+    }
+
+    fn from_constraint(constraint: &str) -> Option<OomScope> {
+        match constraint {
+            "CONSTRAINT_NONE" => Some(OomScope::Global),
+            "CONSTRAINT_MEMCG" => Some(OomScope::Memcg),
+            "CONSTRAINT_CPUSET" => Some(OomScope::Cpuset),
+            "CONSTRAINT_MEMORY_POLICY" => Some(OomScope::Mempolicy),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OomTrigger {
+    pub pid: Option<i32>,
+    pub invocation: parser::OomInvocation,
+    // printed in the header by kernels up to 4.18 only
+    pub cpuset: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Incident {
+    pub trigger: Option<OomTrigger>,
+    pub mem_info: BTreeMap<String, u64>,
+    pub tasks: Vec<parser::TaskEntry>,
+    pub constraint: Option<parser::OomConstraint>,
+    pub memcg_report: bool,
+    pub victim: parser::KilledProcess,
+    pub timestamp_from_system_start: time::Duration,
+}
+
+impl Incident {
+    /// The constraint line is authoritative, older kernels only tell apart memory cgroup reports
+    pub fn scope(&self) -> OomScope {
+        match self
+            .constraint
+            .as_ref()
+            .and_then(|c| OomScope::from_constraint(&c.constraint))
+        {
+            Some(scope) => scope,
+            None if self.memcg_report => OomScope::Memcg,
+            None => OomScope::Global,
+        }
+    }
+
+    /// Whether the header alone proves the oom is not constrained by a memory policy, a cpuset or a memory cgroup,
+    /// only kernels up to 4.18 print the nodemask and the cpuset there
+    pub fn unconstrained_header(&self) -> bool {
+        !self.memcg_report
+            && self.trigger.as_ref().is_some_and(|t| {
+                t.invocation.nodemask.as_deref() == Some("(null)")
+                    && t.cpuset.as_deref() == Some("/")
+            })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Reaping {
+    pub reaped: parser::ReapedProcess,
+    // the kill this reaping completes, if it was observed
+    pub victim: Option<parser::KilledProcess>,
+    pub scope: Option<OomScope>,
+    pub reaping_time: Option<time::Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Report {
+    Kill(Incident),
+    Reaped(Reaping),
+    // the header of the report is printed and its scope known, vm.panic_on_oom stops the host right after
+    Dumping(Incident),
+    // the report ended without a victim, the host is about to hang or reboot
+    Failure(Incident, parser::OomFailure),
+}
+
+// kills waiting for their oom_reaper line, the reaper runs shortly after the kill so few are pending at once
+const MAX_PENDING_REAPINGS: usize = 64;
+
+#[derive(Default)]
+pub struct IncidentAssembler {
+    stage: Stage,
+    current: Incident,
+    task_columns: Vec<String>,
+    pending_reapings: VecDeque<(parser::KilledProcess, OomScope, time::Duration)>,
+}
+
+impl IncidentAssembler {
+    /// Feed a kernel log line to the state machine, a report is returned once its header and its last line are seen
+    pub fn process(&mut self, message: &str, timestamp: time::Duration) -> Option<Report> {
+        if let Some(reaped) = parser::parse_reaped_process(message) {
+            let pending = self
+                .pending_reapings
+                .iter()
+                .position(|(victim, _, _)| victim.pid == reaped.pid)
+                .and_then(|i| self.pending_reapings.remove(i));
+
+            return Some(Report::Reaped(match pending {
+                Some((victim, scope, killed_at)) => Reaping {
+                    reaped,
+                    victim: Some(victim),
+                    scope: Some(scope),
+                    reaping_time: timestamp.checked_sub(killed_at),
+                },
+                None => Reaping {
+                    reaped,
+                    victim: None,
+                    scope: None,
+                    reaping_time: None,
+                },
+            }));
+        }
+
+        if let Some(invocation) = parser::parse_oom_invocation(message) {
+            if self.stage != Stage::Idle {
+                debug!(
+                    "A new oom report started before the previous one was completed, discarding it"
+                );
+            }
+
+            self.current = Incident {
+                trigger: Some(OomTrigger {
+                    pid: None,
+                    invocation,
+                    cpuset: None,
+                }),
+                ..Default::default()
+            };
+            self.stage = Stage::Header;
+            return None;
+        }
+
+        if let Some(failure) = parser::parse_oom_failure(message) {
+            let mut incident = std::mem::take(&mut self.current);
+            self.stage = Stage::Idle;
+            incident.timestamp_from_system_start = timestamp;
+
+            return Some(Report::Failure(incident, failure));
+        }
+
+        if let Some(constraint) = parser::parse_oom_constraint(message) {
+            self.current.constraint = Some(constraint);
+            self.stage = Stage::Summary;
+            return None;
+        }
+
+        /*
+            Memory cgroup reports are announced by one of these lines depending on the kernel version:
+            Task in /docker/1f2e3d killed as a result of limit of /docker/1f2e3d
+            Memory cgroup out of memory: Killed process 4242 (stress) total-vm:4198476kB, ...
+        */
+        if message.contains("Memory cgroup out of memory")
+            || message.contains("killed as a result of limit of")
+        {
+            self.current.memcg_report = true;
+        }
+
+        if let Some(victim) = parser::parse_killed_process(message) {
+            let mut incident = std::mem::take(&mut self.current);
+            self.stage = Stage::Idle;
+
+            // the summary line is printed right before the kill line of the same victim
+            incident.constraint = incident
+                .constraint
+                .take()
+                .filter(|c| c.pid == Some(victim.pid));
+            incident.victim = victim;
+            incident.timestamp_from_system_start = timestamp;
+
+            if self.pending_reapings.len() == MAX_PENDING_REAPINGS {
+                self.pending_reapings.pop_front();
+            }
+            self.pending_reapings
+                .push_back((incident.victim.clone(), incident.scope(), timestamp));
+
+            return Some(Report::Kill(incident));
+        }
+
+        match self.stage {
+            Stage::Idle | Stage::Summary => {}
+            Stage::Header => {
+                if message.trim_start().starts_with("Mem-Info:") {
+                    self.stage = Stage::MemInfo;
+                    return Some(Report::Dumping(self.current.clone()));
+                } else if let Some(columns) = parser::parse_task_table_header(message) {
+                    // memory cgroup reports print the usage of the cgroup instead of the Mem-Info dump
+                    self.task_columns = columns;
+                    self.stage = Stage::TaskTable;
+                    self.current.memcg_report = true;
+                    return Some(Report::Dumping(self.current.clone()));
+                } else if message.trim_start().starts_with("Tasks state") {
+                    self.stage = Stage::TaskTable;
+                    self.current.memcg_report = true;
+                    return Some(Report::Dumping(self.current.clone()));
+                } else if let Some(cpuset) = parser::parse_cpuset(message) {
+                    if let Some(trigger) = self.current.trigger.as_mut() {
+                        trigger.cpuset.get_or_insert(cpuset);
+                    }
+                } else if let Some(pid) = parser::parse_stack_dump_pid(message) {
+                    if let Some(trigger) = self.current.trigger.as_mut() {
+                        trigger.pid.get_or_insert(pid);
+                    }
+                }
+            }
+            Stage::MemInfo => {
+                if let Some(columns) = parser::parse_task_table_header(message) {
+                    self.task_columns = columns;
+                    self.stage = Stage::TaskTable;
+                } else if message.trim_start().starts_with("Tasks state") {
+                    self.stage = Stage::TaskTable;
+                } else {
+                    for (counter, value) in parser::parse_mem_info_counters(message) {
+                        self.current.mem_info.entry(counter).or_insert(value);
+                    }
+                }
+            }
+            Stage::TaskTable => {
+                if let Some(columns) = parser::parse_task_table_header(message) {
+                    self.task_columns = columns;
+                } else if let Some(task) = parser::parse_task_table_row(message, &self.task_columns)
+                {
+                    self.current.tasks.push(task);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPORT: &str = "stress invoked oom-killer: gfp_mask=0x100cca(GFP_HIGHUSER_MOVABLE), order=0, oom_score_adj=0
+CPU: 1 PID: 4242 Comm: stress Not tainted 5.10.0-8-amd64 #1 Debian 5.10.46-4
+Call Trace:
+ dump_stack+0x6b/0x83
+Mem-Info:
+active_anon:2008 inactive_anon:981349 isolated_anon:0
+ free:21130 free_pcp:59 free_cma:0
+Tasks state (memory values in pages):
+[  pid  ]   uid  tgid total_vm      rss pgtables_bytes swapents oom_score_adj name
+[    287]     0   287     8307      260    81920        0          -250 systemd-journal
+[   4242]     0  4242  1049619   981160  7925760        0             0 stress
+oom-kill:constraint=CONSTRAINT_NONE,nodemask=(null),cpuset=/,mems_allowed=0,global_oom,task_memcg=/user.slice,task=stress,pid=4242,uid=0
+Out of memory: Killed process 4242 (stress) total-vm:4198476kB, anon-rss:3924640kB, file-rss:8kB, shmem-rss:0kB, UID:0 pgtables:7740kB oom_score_adj:0";
+
+    // the kills assembled from the lines of a report, all logged at the same time
+    fn kills<'a>(lines: impl IntoIterator<Item = &'a str>) -> Vec<Incident> {
+        let mut assembler = IncidentAssembler::default();
+        lines
+            .into_iter()
+            .filter_map(
+                |line| match assembler.process(line, time::Duration::from_secs(0)) {
+                    Some(Report::Kill(incident)) => Some(incident),
+                    _ => None,
+                },
+            )
+            .collect()
+    }
+
+    #[test]
+    fn assembled_incident() {
+        let incidents = kills(REPORT.lines());
+        assert_eq!(incidents.len(), 1);
+
+        let incident = &incidents[0];
+        let trigger = incident.trigger.as_ref().unwrap();
+        assert_eq!(trigger.pid, Some(4242));
+        assert_eq!(trigger.invocation.comm, "stress");
+        assert_eq!(incident.mem_info.get("active_anon"), Some(&2008));
+        assert_eq!(incident.mem_info.get("free"), Some(&21130));
+        assert_eq!(
+            incident
+                .tasks
+                .iter()
+                .map(|t| (t.pid, t.rss_pages, t.name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (287, Some(260), "systemd-journal"),
+                (4242, Some(981160), "stress")
+            ]
+        );
+        assert_eq!(
+            incident.constraint.as_ref().map(|c| c.constraint.as_str()),
+            Some("CONSTRAINT_NONE")
+        );
+        assert_eq!(incident.victim.pid, 4242);
+    }
+
+    #[test]
+    fn interrupted_report() {
+        let incidents = kills(vec![
+            "stress invoked oom-killer: gfp_mask=0x100cca(GFP_HIGHUSER_MOVABLE), order=0, oom_score_adj=0",
+            "CPU: 1 PID: 4242 Comm: stress Not tainted 5.10.0-8-amd64 #1 Debian 5.10.46-4",
+            "java invoked oom-killer: gfp_mask=0x100cca(GFP_HIGHUSER_MOVABLE), order=0, oom_score_adj=0",
+            "CPU: 0 PID: 777 Comm: java Not tainted 5.10.0-8-amd64 #1 Debian 5.10.46-4",
+            // the summary of another victim is not attached to the kill
+            "oom-kill:constraint=CONSTRAINT_NONE,nodemask=(null),cpuset=/,mems_allowed=0,global_oom,task_memcg=/,task=stress,pid=4242,uid=0",
+            "Out of memory: Killed process 778 (java) total-vm:1000kB, anon-rss:500kB",
+        ]);
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].trigger.as_ref().and_then(|t| t.pid), Some(777));
+        assert!(incidents[0].constraint.is_none());
+        assert_eq!(incidents[0].victim.pid, 778);
+    }
+
+    #[test]
+    fn incident_scopes() {
+        let reports = vec![
+            (REPORT.to_string(), OomScope::Global),
+            // older kernels print no summary line, only the memory cgroup announcement
+            (
+                [
+                    "node invoked oom-killer: gfp_mask=0x14000c0(GFP_KERNEL), nodemask=(null), order=0, oom_score_adj=999",
+                    "Task in /kubepods/pod5f1c0a3e/7d4f2e0c0a1b killed as a result of limit of /kubepods/pod5f1c0a3e",
+                    "Memory cgroup out of memory: Kill process 30114 (node) score 1986 or sacrifice child",
+                    "Killed process 30114 (node) total-vm:1461992kB, anon-rss:256528kB",
+                ]
+                .join("\n"),
+                OomScope::Memcg,
+            ),
+            (
+                [
+                    "java invoked oom-killer: gfp_mask=0x100cca(GFP_HIGHUSER_MOVABLE), order=0, oom_score_adj=0",
+                    "oom-kill:constraint=CONSTRAINT_CPUSET,nodemask=(null),cpuset=batch,mems_allowed=1,task_memcg=/,task=java,pid=777,uid=0",
+                    "Out of memory: Killed process 777 (java) total-vm:1000kB, anon-rss:500kB",
+                ]
+                .join("\n"),
+                OomScope::Cpuset,
+            ),
+        ];
+
+        for (i, (report, scope)) in reports.into_iter().enumerate() {
+            let scopes: Vec<OomScope> = kills(report.lines())
+                .iter()
+                .map(|incident| incident.scope())
+                .collect();
+            assert_eq!(scopes, vec![scope], "report {}", i);
+        }
+    }
+
+    #[test]
+    fn reaping_linked_to_kill() {
+        let mut assembler = IncidentAssembler::default();
+        for line in REPORT.lines() {
+            assembler.process(line, time::Duration::from_micros(5678900460));
+        }
+        let report = assembler.process(
+            "oom_reaper: reaped process 4242 (stress), now anon-rss:0kB, file-rss:0kB, shmem-rss:0kB",
+            time::Duration::from_micros(5678905161),
+        );
+
+        match report {
+            Some(Report::Reaped(reaping)) => {
+                assert_eq!(reaping.reaped.pid, 4242);
+                assert_eq!(reaping.victim.map(|v| v.comm), Some("stress".to_string()));
+                assert_eq!(reaping.scope, Some(OomScope::Global));
+                assert_eq!(
+                    reaping.reaping_time,
+                    Some(time::Duration::from_micros(4701))
+                );
+            }
+            report => panic!("expected the reaping, got {:?}", report),
+        }
+
+        // the pending kill is completed only once
+        let report = assembler.process(
+            "oom_reaper: reaped process 4242 (stress), now anon-rss:0kB, file-rss:0kB, shmem-rss:0kB",
+            time::Duration::from_micros(5678905200),
+        );
+        match report {
+            Some(Report::Reaped(reaping)) => assert!(reaping.victim.is_none()),
+            report => panic!("expected the reaping, got {:?}", report),
+        }
+    }
+    #[test]
+    fn reaping_without_kill() {
+        let mut assembler = IncidentAssembler::default();
+        let report = assembler.process(
+            "oom_reaper: reaped process 4242 (stress), now anon-rss:0kB, file-rss:0kB, shmem-rss:0kB",
+            time::Duration::from_secs(1),
+        );
+
+        match report {
+            Some(Report::Reaped(reaping)) => {
+                assert_eq!(reaping.reaped.pid, 4242);
+                assert!(reaping.victim.is_none());
+                assert!(reaping.scope.is_none());
+                assert!(reaping.reaping_time.is_none());
+            }
+            report => panic!("expected the reaping, got {:?}", report),
+        }
+    }
+
+    #[test]
+    fn failure_reports() {
+        let failures = vec![
+            (
+                "Out of memory and no killable processes...",
+                parser::OomFailure::NoKillableProcesses,
+            ),
+            (
+                "Kernel panic - not syncing: Out of memory: system-wide panic_on_oom is enabled",
+                parser::OomFailure::Panic,
+            ),
+            (
+                "Kernel panic - not syncing: System is deadlocked on memory",
+                parser::OomFailure::Panic,
+            ),
+        ];
+
+        for (i, (line, expected)) in failures.into_iter().enumerate() {
+            let mut assembler = IncidentAssembler::default();
+            // the header ends at Mem-Info, where the dump is announced
+            let reports: Vec<Report> = REPORT
+                .lines()
+                .take(5)
+                .filter_map(|line| assembler.process(line, time::Duration::from_secs(1)))
+                .collect();
+            assert!(matches!(reports[..], [Report::Dumping(_)]), "failure {}", i);
+
+            match assembler.process(line, time::Duration::from_secs(2)) {
+                Some(Report::Failure(incident, failure)) => {
+                    assert_eq!(failure, expected, "failure {}", i);
+                    assert_eq!(
+                        incident.trigger.and_then(|t| t.pid),
+                        Some(4242),
+                        "failure {}",
+                        i
+                    );
+                    assert_eq!(
+                        incident.timestamp_from_system_start,
+                        time::Duration::from_secs(2),
+                        "failure {}",
+                        i
+                    );
+                }
+                report => panic!("failure {}: expected a failure, got {:?}", i, report),
+            }
+
+            // the report is over, a later kill line is not attached to it
+            match assembler.process(
+                "Out of memory: Killed process 778 (java) total-vm:1000kB, anon-rss:500kB",
+                time::Duration::from_secs(3),
+            ) {
+                Some(Report::Kill(incident)) => {
+                    assert!(incident.trigger.is_none(), "failure {}", i)
+                }
+                report => panic!("failure {}: expected a kill, got {:?}", i, report),
+            }
+        }
+    }
+
+    // the messages of a dmesg excerpt with their timestamp, the continuation lines keep the one of their record
+    fn records(excerpt: &str) -> Vec<(&str, time::Duration)> {
+        let mut timestamp = time::Duration::from_secs(0);
+        excerpt
+            .lines()
+            .map(|line| {
+                if let Some((stamp, message)) = line
+                    .strip_prefix('[')
+                    .and_then(|rest| rest.split_once("] "))
+                {
+                    let (secs, micros) = stamp.trim().split_once('.').unwrap();
+                    timestamp = time::Duration::from_secs(secs.parse().unwrap())
+                        + time::Duration::from_micros(micros.parse().unwrap());
+                    (message, timestamp)
+                } else {
+                    (line, timestamp)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn assembled_reports() {
+        // fixture name and excerpt, trigger pid, task table pids, scope, oom_reaper delay in microseconds
+        let fixtures = vec![
+            (
+                "4.15-ubuntu1804-memcg",
+                include_str!("../tests/fixtures/oom/4.15-ubuntu1804-memcg.log"),
+                30120,
+                vec![30042, 30114],
+                OomScope::Memcg,
+                11231,
+            ),
+            (
+                "5.10-debian11",
+                include_str!("../tests/fixtures/oom/5.10-debian11.log"),
+                4242,
+                vec![287, 4242],
+                OomScope::Global,
+                4701,
+            ),
+            (
+                "6.1-memcg",
+                include_str!("../tests/fixtures/oom/6.1-memcg.log"),
+                9981,
+                vec![9981],
+                OomScope::Memcg,
+                8071,
+            ),
+        ];
+
+        for (name, excerpt, trigger_pid, task_pids, scope, reaping_micros) in fixtures {
+            let mut assembler = IncidentAssembler::default();
+            let reports: Vec<Report> = records(excerpt)
+                .into_iter()
+                .filter_map(|(message, timestamp)| assembler.process(message, timestamp))
+                .collect();
+            assert_eq!(reports.len(), 3, "fixture {}", name);
+
+            match &reports[0] {
+                Report::Dumping(incident) => {
+                    assert_eq!(incident.scope(), scope, "fixture {}", name)
+                }
+                report => panic!("fixture {}: expected the dump, got {:?}", name, report),
+            }
+
+            let victim = match &reports[1] {
+                Report::Kill(incident) => {
+                    assert_eq!(
+                        incident.trigger.as_ref().and_then(|t| t.pid),
+                        Some(trigger_pid),
+                        "fixture {}",
+                        name
+                    );
+                    assert_eq!(
+                        incident.tasks.iter().map(|t| t.pid).collect::<Vec<i32>>(),
+                        task_pids,
+                        "fixture {}",
+                        name
+                    );
+                    assert_eq!(incident.scope(), scope, "fixture {}", name);
+                    incident.victim.clone()
+                }
+                report => panic!("fixture {}: expected the kill, got {:?}", name, report),
+            };
+
+            match &reports[2] {
+                Report::Reaped(reaping) => {
+                    assert_eq!(reaping.reaped.pid, victim.pid, "fixture {}", name);
+                    assert_eq!(reaping.victim.as_ref(), Some(&victim), "fixture {}", name);
+                    assert_eq!(reaping.scope, Some(scope), "fixture {}", name);
+                    assert_eq!(
+                        reaping.reaping_time,
+                        Some(time::Duration::from_micros(reaping_micros)),
+                        "fixture {}",
+                        name
+                    );
+                }
+                report => panic!("fixture {}: expected the reaping, got {:?}", name, report),
+            }
+        }
+    }
+}
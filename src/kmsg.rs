@@ -0,0 +1,224 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::time;
+
+pub const KMSG_PATH: &str = "/dev/kmsg";
+
+// the kernel never produces records longer than this, a read with a smaller buffer fails with EINVAL
+const MAX_RECORD_SIZE: usize = 8192;
+
+/*
+    Example of a record read from /dev/kmsg, the header is followed by the message and optional dictionary lines:
+    6,1234,5678901234,-;Out of memory: Killed process 9865 (oom_trigger) total-vm:7468696kB, ...
+     SUBSYSTEM=...
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KmsgRecord {
+    pub level: u8,
+    pub facility: u8,
+    pub sequence: u64,
+    pub timestamp_from_system_start: time::Duration,
+    pub message: String,
+}
+
+// the kernel escapes non printable characters (including the new lines of multi-line messages) as \xNN
+fn unescape(message: &str) -> String {
+    let bytes = message.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1] == b'x' {
+            let hex = std::str::from_utf8(&bytes[i + 2..i + 4]).unwrap_or("");
+            if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                unescaped.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+        unescaped.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&unescaped).to_string()
+}
+
+pub fn parse_record(raw: &str) -> Option<KmsgRecord> {
+    let (header, rest) = raw.split_once(';')?;
+    let mut fields = header.split(',');
+
+    let priority = fields.next()?.parse::<u32>().ok()?;
+    let sequence = fields.next()?.parse::<u64>().ok()?;
+    let timestamp = fields.next()?.parse::<u64>().ok()?;
+
+    // dictionary lines (prefixed by a space) are not part of the message
+    let message = rest.split('\n').next().unwrap_or("");
+
+    Some(KmsgRecord {
+        level: (priority & 7) as u8,
+        facility: (priority >> 3) as u8,
+        sequence,
+        timestamp_from_system_start: time::Duration::from_micros(timestamp),
+        message: unescape(message),
+    })
+}
+
+// how a record follows the last one processed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Continuity {
+    Next,
+    // already processed, e.g. read again after the kernel log was reopened
+    Duplicate,
+    // the records in between were lost
+    Gap { first: u64, last: u64 },
+}
+
+pub fn continuity(last_sequence: Option<u64>, sequence: u64) -> Continuity {
+    match last_sequence {
+        Some(last) if sequence <= last => Continuity::Duplicate,
+        Some(last) if sequence > last + 1 => Continuity::Gap {
+            first: last + 1,
+            last: sequence - 1,
+        },
+        _ => Continuity::Next,
+    }
+}
+
+pub struct KmsgReader {
+    file: File,
+    buffer: Vec<u8>,
+}
+
+impl KmsgReader {
+    pub fn open(path: &str) -> io::Result<KmsgReader> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+
+        Ok(KmsgReader {
+            file,
+            buffer: vec![0; MAX_RECORD_SIZE],
+        })
+    }
+
+    /// Skip every record currently in the ring buffer, only the ones logged afterwards will be read
+    pub fn seek_end(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::End(0)).map(|_| ())
+    }
+
+    /// Wait up to timeout for the next record, Ok(None) is returned if nothing was logged in the meantime
+    pub fn read(&mut self, timeout: time::Duration) -> io::Result<Option<KmsgRecord>> {
+        loop {
+            match self.file.read(&mut self.buffer) {
+                Ok(0) => return Ok(None),
+                Ok(size) => {
+                    let raw = String::from_utf8_lossy(&self.buffer[..size]);
+                    match parse_record(&raw) {
+                        Some(record) => return Ok(Some(record)),
+                        None => {
+                            warn!(
+                                "Skipping malformed record read from the kernel log: {}",
+                                raw
+                            );
+                            continue;
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if !self.wait(timeout)? {
+                        return Ok(None);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn wait(&self, timeout: time::Duration) -> io::Result<bool> {
+        let mut fds = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        match unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) } {
+            -1 => {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    return Ok(false);
+                }
+                Err(e)
+            }
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_with_dictionary() {
+        let record = parse_record(
+            "30,1234,5678901234,-;first line\\x0asecond line \\x5c\n SUBSYSTEM=pci\n DEVICE=+pci:0000:00:1f.0\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            record,
+            KmsgRecord {
+                level: 6,
+                facility: 3,
+                sequence: 1234,
+                timestamp_from_system_start: time::Duration::from_micros(5678901234),
+                message: "first line\nsecond line \\".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn malformed_records() {
+        assert_eq!(parse_record("no header at all"), None);
+        assert_eq!(parse_record("6,abc,5678901234,-;message"), None);
+        assert_eq!(parse_record("6,1234;message"), None);
+    }
+
+    #[test]
+    fn record_continuity() {
+        let cases = vec![
+            // the first record read, whatever its sequence
+            (None, 0, Continuity::Next),
+            (None, 1234, Continuity::Next),
+            (Some(1234), 1235, Continuity::Next),
+            (Some(1234), 1234, Continuity::Duplicate),
+            (Some(1234), 17, Continuity::Duplicate),
+            (
+                Some(1234),
+                1236,
+                Continuity::Gap {
+                    first: 1235,
+                    last: 1235,
+                },
+            ),
+            (
+                Some(1234),
+                2000,
+                Continuity::Gap {
+                    first: 1235,
+                    last: 1999,
+                },
+            ),
+        ];
+
+        for (i, (last_sequence, sequence, expected)) in cases.into_iter().enumerate() {
+            assert_eq!(continuity(last_sequence, sequence), expected, "case {}", i);
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use serde_json::json;
+use tokio::runtime::Runtime;
+
+/*
+    The pods of the node are listed by the kubelet (https://127.0.0.1:10250/pods) or by the API server
+    (https://kubernetes.default.svc/api/v1/pods?fieldSelector=spec.nodeName=<node>), both answer with a PodList:
+    {"items": [{"metadata": {"name": "api-7f9c", "namespace": "payments", "uid": "0f1c...", "labels": {...},
+                             "ownerReferences": [{"kind": "ReplicaSet", "name": "api-5d8b", "controller": true}]},
+                "spec": {"nodeName": "node-1"},
+                "status": {"containerStatuses": [{"name": "api", "containerID": "containerd://3f4e...a1"}]}}]}
+*/
+
+pub const SERVICE_ACCOUNT_TOKEN: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+pub const SERVICE_ACCOUNT_CA: &str = "/var/run/secrets/kubernetes.io/serviceaccount/ca.crt";
+
+// the containers killed just before a pod is deleted or restarted are still looked up for a while
+const STALE_AFTER: time::Duration = time::Duration::from_secs(600);
+
+const CONTAINER_STATUSES: &[&str] = &[
+    "containerStatuses",
+    "initContainerStatuses",
+    "ephemeralContainerStatuses",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PodMetadata {
+    pub namespace: String,
+    pub pod: String,
+    pub uid: String,
+    // unknown when the pod is found by its uid only
+    pub container: Option<String>,
+    pub workload_kind: Option<String>,
+    pub workload_name: Option<String>,
+    pub node: Option<String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+// the pods keyed by uid and their containers keyed by id
+#[derive(Debug, Default)]
+pub struct NodePods {
+    pub pods: HashMap<String, PodMetadata>,
+    pub containers: HashMap<String, PodMetadata>,
+}
+
+#[derive(Default)]
+struct Pods {
+    containers: HashMap<String, (PodMetadata, time::Instant)>,
+    pods: HashMap<String, (PodMetadata, time::Instant)>,
+}
+
+// the runtime prefix of a container id (containerd://, cri-o://, docker://) is not part of the cgroup path
+fn strip_runtime(container_id: &str) -> &str {
+    match container_id.split_once("://") {
+        Some((_, id)) => id,
+        None => container_id,
+    }
+}
+
+// the pods of a Deployment are owned by one of its ReplicaSets, named after the pod-template-hash label
+fn get_workload(
+    metadata: &serde_json::Value,
+    labels: &serde_json::Map<String, serde_json::Value>,
+) -> (Option<String>, Option<String>) {
+    let owner = match metadata["ownerReferences"].as_array().and_then(|owners| {
+        owners
+            .iter()
+            .find(|o| o["controller"].as_bool().unwrap_or(false))
+            .or_else(|| owners.first())
+    }) {
+        Some(owner) => owner,
+        None => return (None, None),
+    };
+
+    let kind = owner["kind"].as_str().map(str::to_string);
+    let name = owner["name"].as_str().map(str::to_string);
+    let hash = labels
+        .get("pod-template-hash")
+        .and_then(|h| h.as_str())
+        .map(|h| format!("-{}", h));
+
+    match (kind.as_deref(), name.as_deref(), hash) {
+        (Some("ReplicaSet"), Some(name), Some(hash)) if name.ends_with(&hash) => (
+            Some("Deployment".to_string()),
+            Some(name.trim_end_matches(&hash).to_string()),
+        ),
+        _ => (kind, name),
+    }
+}
+
+pub fn parse_pod_list(
+    pod_list: &serde_json::Value,
+    selected_labels: &[String],
+) -> Result<NodePods, String> {
+    let items = match pod_list["items"].as_array() {
+        Some(items) => items,
+        None => return Err("The pod list has no items".to_string()),
+    };
+
+    let mut node_pods = NodePods::default();
+    let no_labels = serde_json::Map::new();
+
+    for item in items {
+        let metadata = &item["metadata"];
+        let (namespace, name, uid) = match (
+            metadata["namespace"].as_str(),
+            metadata["name"].as_str(),
+            metadata["uid"].as_str(),
+        ) {
+            (Some(namespace), Some(name), Some(uid)) => (namespace, name, uid),
+            _ => continue,
+        };
+        let labels = metadata["labels"].as_object().unwrap_or(&no_labels);
+        let (workload_kind, workload_name) = get_workload(metadata, labels);
+
+        let pod = PodMetadata {
+            namespace: namespace.to_string(),
+            pod: name.to_string(),
+            uid: uid.to_string(),
+            container: None,
+            workload_kind,
+            workload_name,
+            node: item["spec"]["nodeName"].as_str().map(str::to_string),
+            labels: selected_labels
+                .iter()
+                .filter_map(|l| {
+                    labels
+                        .get(l)
+                        .and_then(|v| v.as_str())
+                        .map(|v| (l.clone(), v.to_string()))
+                })
+                .collect(),
+        };
+
+        for statuses in CONTAINER_STATUSES {
+            for status in item["status"][statuses].as_array().into_iter().flatten() {
+                let container = status["name"].as_str().map(str::to_string);
+                // a container killed and restarted is known by its previous id too
+                let ids = [
+                    &status["containerID"],
+                    &status["lastState"]["terminated"]["containerID"],
+                ];
+
+                for id in ids.iter().filter_map(|id| id.as_str()) {
+                    let mut metadata = pod.clone();
+                    metadata.container = container.clone();
+                    node_pods
+                        .containers
+                        .insert(strip_runtime(id).to_string(), metadata);
+                }
+            }
+        }
+
+        node_pods.pods.insert(uid.to_string(), pod);
+    }
+
+    Ok(node_pods)
+}
+
+async fn fetch_pod_list(
+    url: &str,
+    token_file: Option<&str>,
+    ca_file: Option<&str>,
+    insecure: bool,
+) -> Result<serde_json::Value, String> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(time::Duration::from_secs(10))
+        .danger_accept_invalid_certs(insecure);
+    if let Some(ca_file) = ca_file {
+        let certificate = match fs::read(ca_file).map(|pem| reqwest::Certificate::from_pem(&pem)) {
+            Ok(Ok(certificate)) => certificate,
+            Ok(Err(e)) => return Err(format!("Invalid CA certificate in {}: {}", ca_file, e)),
+            Err(e) => return Err(format!("Could not read {}: {}", ca_file, e)),
+        };
+        builder = builder.add_root_certificate(certificate);
+    }
+    let client = match builder.build() {
+        Ok(client) => client,
+        Err(e) => return Err(format!("Could not create the HTTP client: {}", e)),
+    };
+
+    let mut request = client.get(url);
+    // the projected service account tokens are rotated, the file is read again on every request
+    if let Some(token_file) = token_file {
+        match fs::read_to_string(token_file) {
+            Ok(token) => request = request.bearer_auth(token.trim()),
+            Err(e) => return Err(format!("Could not read {}: {}", token_file, e)),
+        }
+    }
+
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => return Err(e.to_string()),
+    };
+    if response.status() != 200 {
+        return Err(format!("Status code is {}", response.status()));
+    }
+
+    match response.json::<serde_json::Value>().await {
+        Ok(pod_list) => Ok(pod_list),
+        Err(e) => Err(format!("Invalid pod list: {}", e)),
+    }
+}
+
+#[derive(Clone)]
+pub struct PodDirectory {
+    pub url: String,
+    pub token_file: Option<String>,
+    pub ca_file: Option<String>,
+    pub insecure: bool,
+    pub labels: Vec<String>,
+    pods: Arc<Mutex<Pods>>,
+}
+
+impl PodDirectory {
+    pub fn new(
+        url: String,
+        token_file: Option<String>,
+        ca_file: Option<String>,
+        insecure: bool,
+        labels: Vec<String>,
+    ) -> PodDirectory {
+        PodDirectory {
+            url,
+            token_file,
+            ca_file,
+            insecure,
+            labels,
+            pods: Arc::new(Mutex::new(Pods::default())),
+        }
+    }
+
+    /// List the pods of the node again, the ones gone for a while are forgotten
+    pub fn refresh(&self) -> Result<usize, String> {
+        let pod_list = match Runtime::new() {
+            Ok(rt) => rt.block_on(fetch_pod_list(
+                &self.url,
+                self.token_file.as_deref(),
+                self.ca_file.as_deref(),
+                self.insecure,
+            )),
+            Err(e) => Err(format!("Could not create a tokyo runtime instance: {}", e)),
+        };
+        let pod_list = match pod_list {
+            Ok(pod_list) => pod_list,
+            Err(e) => return Err(format!("Could not list the pods from {}: {}", self.url, e)),
+        };
+        let node_pods = parse_pod_list(&pod_list, &self.labels)?;
+        let listed = node_pods.pods.len();
+
+        let now = time::Instant::now();
+        match self.pods.lock() {
+            Ok(mut known) => {
+                known
+                    .pods
+                    .retain(|_, (_, seen)| seen.elapsed() < STALE_AFTER);
+                known
+                    .containers
+                    .retain(|_, (_, seen)| seen.elapsed() < STALE_AFTER);
+                known
+                    .pods
+                    .extend(node_pods.pods.into_iter().map(|(k, v)| (k, (v, now))));
+                known
+                    .containers
+                    .extend(node_pods.containers.into_iter().map(|(k, v)| (k, (v, now))));
+            }
+            Err(e) => {
+                return Err(format!(
+                "Could not acquire the pods table lock in the pods-refresher thread!. Error: {}",
+                e
+            ))
+            }
+        }
+
+        Ok(listed)
+    }
+
+    pub fn lookup(&self, container_id: Option<&str>, pod_uid: Option<&str>) -> Option<PodMetadata> {
+        let pods = match self.pods.lock() {
+            Ok(pods) => pods,
+            Err(e) => {
+                error!("Could not acquire the pods table lock!. Error: {}", e);
+                return None;
+            }
+        };
+
+        container_id
+            .and_then(|id| pods.containers.get(id))
+            .or_else(|| pod_uid.and_then(|uid| pods.pods.get(uid)))
+            .map(|(metadata, _)| metadata.clone())
+    }
+
+    /// Add the metadata of the pod to an event naming a container or a pod
+    pub fn enrich(&self, event: &serde_json::Value) -> Option<serde_json::Value> {
+        let metadata = self.lookup(event["container_id"].as_str(), event["pod_uid"].as_str())?;
+
+        let mut enriched = event.clone();
+        enriched["kubernetes"] = json!({ "namespace": metadata.namespace,
+                                         "pod": metadata.pod,
+                                         "container": metadata.container,
+                                         "workload_kind": metadata.workload_kind,
+                                         "workload_name": metadata.workload_name,
+                                         "node": metadata.node,
+                                         "labels": metadata.labels });
+        Some(enriched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    const CONTAINER_ID: &str = "3f4e5d6c7b8a99887766554433221100ffeeddccbbaa00112233445566778899";
+    const PREVIOUS_CONTAINER_ID: &str =
+        "a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f90";
+    const POD_UID: &str = "0f1c2d3e-4b5a-6978-8a9b-0c1d2e3f4a5b";
+
+    fn pod_list() -> serde_json::Value {
+        json!({ "kind": "PodList",
+                "items": [{ "metadata": { "name": "api-5d8b7c9f6-x2x9z",
+                                          "namespace": "payments",
+                                          "uid": POD_UID,
+                                          "labels": { "app": "api",
+                                                      "team": "checkout",
+                                                      "pod-template-hash": "5d8b7c9f6" },
+                                          "ownerReferences": [{ "kind": "ReplicaSet",
+                                                                "name": "api-5d8b7c9f6",
+                                                                "controller": true }] },
+                            "spec": { "nodeName": "node-1" },
+                            "status": { "containerStatuses": [{
+                                "name": "api",
+                                "containerID": format!("containerd://{}", CONTAINER_ID),
+                                "lastState": { "terminated": {
+                                    "reason": "OOMKilled",
+                                    "containerID": format!("containerd://{}", PREVIOUS_CONTAINER_ID) } } }] } }] })
+    }
+
+    // answers a single request with the pod list, like the API server would
+    fn serve_pod_list() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 4096];
+            let _ = stream.read(&mut request).unwrap();
+
+            let body = pod_list().to_string();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+
+        format!(
+            "http://{}/api/v1/pods?fieldSelector=spec.nodeName=node-1",
+            address
+        )
+    }
+
+    #[test]
+    fn pod_directory_refresh_and_enrich() {
+        let directory = PodDirectory::new(
+            serve_pod_list(),
+            None,
+            None,
+            false,
+            vec!["team".to_string(), "missing".to_string()],
+        );
+        assert_eq!(directory.refresh(), Ok(1));
+
+        let event =
+            json!({ "event": "oom_kill", "container_id": CONTAINER_ID, "pod_uid": POD_UID });
+        let enriched = directory.enrich(&event).unwrap();
+        assert_eq!(
+            enriched["kubernetes"],
+            json!({ "namespace": "payments",
+                    "pod": "api-5d8b7c9f6-x2x9z",
+                    "container": "api",
+                    "workload_kind": "Deployment",
+                    "workload_name": "api",
+                    "node": "node-1",
+                    "labels": { "team": "checkout" } })
+        );
+
+        // the container killed before the restart is still known by its previous id
+        let previous = directory.lookup(Some(PREVIOUS_CONTAINER_ID), None).unwrap();
+        assert_eq!(previous.container.as_deref(), Some("api"));
+        assert_eq!(previous.pod, "api-5d8b7c9f6-x2x9z");
+
+        // a pod known by its uid only (e.g. the cgroup of the pod itself) has no container
+        let pod = directory.lookup(Some("unknown"), Some(POD_UID)).unwrap();
+        assert_eq!(pod.container, None);
+        assert_eq!(pod.workload_kind.as_deref(), Some("Deployment"));
+
+        assert_eq!(directory.enrich(&json!({ "event": "oom_kill" })), None);
+    }
+}
@@ -1,4 +1,4 @@
-use std::env;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
@@ -8,111 +8,169 @@ use std::time;
 use clap::{App, Arg};
 use env_logger::Env;
 use lru::LruCache;
-use rmesg::log_entries;
-use rmesg::Backend;
-use serde_json::json;
 use signal_hook::flag;
-use tokio::runtime::Runtime;
 
+mod container;
+mod events;
+mod incident;
+mod kmsg;
+mod kubernetes;
+mod memcg;
+mod metrics;
 mod notifiers;
+mod parser;
+mod pipeline;
+mod proc_connector;
+mod process;
+mod psi;
+mod replay;
+mod rules;
+mod state;
+mod userspace;
+mod vmstat;
 
 #[macro_use]
 extern crate log;
 
-fn is_string_numeric(str: String) -> bool {
-    for c in str.chars() {
-        if !c.is_numeric() {
-            return false;
-        }
-    }
-    return true;
-}
-
-fn get_uptime() -> Result<time::Duration, String> {
-    match fs::read_to_string("/proc/uptime") {
-        Err(e) => return Err(format!("Could not read /proc/uptime: {}", e)),
-        Ok(content) => {
-            let uptime = time::Duration::from_secs_f32(
-                content
-                    .split_whitespace()
-                    .next()
-                    .unwrap_or("0")
-                    .parse::<f32>()
-                    .unwrap_or(0.0),
-            );
-
-            return Ok(uptime);
-        }
-    }
-}
+// how often the kernel log reader wakes up to check for termination when nothing is logged
+const KMSG_POLL_TIMEOUT: time::Duration = time::Duration::from_millis(1000);
 
 fn get_pid_max() -> Result<usize, String> {
     match fs::read_to_string("/proc/sys/kernel/pid_max") {
-        Err(e) => return Err(format!("Could not read /proc/sys/kernel/pid_max: {}", e)),
-        Ok(content) => return Ok(content.trim().parse::<usize>().unwrap()), // this is guaranteed to be an integer
+        Err(e) => Err(format!("Could not read /proc/sys/kernel/pid_max: {}", e)),
+        Ok(content) => Ok(content.trim().parse::<usize>().unwrap()), // this is guaranteed to be an integer
     }
 }
 
-fn get_hostname() -> String {
-    match env::var("HOSTNAME") {
-        Ok(val) => return val,
-        Err(_) => match fs::read_to_string("/proc/sys/kernel/hostname") {
-            Ok(host_name) => return host_name.trim().to_string(),
-            Err(e) => {
-                error!(
-                    "Could not read /proc/sys/kernel/hostname to obtain the hostname: {}",
-                    e
-                );
+fn refresh_processes(processes: &Mutex<LruCache<i32, process::ProcessInfo>>) {
+    // /proc is read without holding the lock, the kernel log reader needs the cache during an oom
+    let snapshots: Vec<(i32, process::ProcessInfo)> = match procfs::process::all_processes() {
+        Ok(procs_list) => procs_list
+            .iter()
+            .map(|proc| (proc.stat.pid, process::ProcessInfo::read(proc)))
+            .collect(),
+        Err(e) => {
+            error!("Could not list the processes running on the host: {}", e);
+            return;
+        }
+    };
+
+    match processes.lock() {
+        Ok(mut procs) => {
+            // the cached processes missing from /proc exited since the last refresh
+            let alive: HashSet<i32> = snapshots.iter().map(|(pid, _)| *pid).collect();
+            let exited: Vec<i32> = procs
+                .iter()
+                .filter(|(pid, p)| p.exit_time.is_none() && !alive.contains(pid))
+                .map(|(pid, _)| *pid)
+                .collect();
+            for pid in exited {
+                process::exited(&mut procs, pid);
+            }
 
-                return "N/A".to_string();
+            for (pid, info) in snapshots {
+                debug!(
+                    "Adding/Overwriting process {} with command line: {}",
+                    pid, info.cmdline
+                );
+                process::cache(&mut procs, pid, info);
             }
-        },
+        }
+        Err(e) => error!(
+            "Could not acquire the process table lock in the process-refresher thread!. Error: {}",
+            e
+        ),
     }
 }
 
-fn get_kernel_version() -> String {
-    match fs::read_to_string("/proc/version") {
-        Ok(kernel_version) => return kernel_version.trim().to_string(),
-        Err(e) => {
-            error!(
-                "Could not read /proc/version to obtain the kernel version: {}",
-                e
+fn cache_process(processes: &Mutex<LruCache<i32, process::ProcessInfo>>, pid: i32) {
+    // a process that already exited keeps the snapshot cached before
+    let info = match procfs::process::Process::new(pid) {
+        Ok(proc) => process::ProcessInfo::read(&proc),
+        Err(_) => return,
+    };
+
+    match processes.lock() {
+        Ok(mut procs) => {
+            debug!(
+                "Adding/Overwriting process {} with command line: {}",
+                pid, info.cmdline
             );
-
-            return "N/A".to_string();
+            process::cache(&mut procs, pid, info);
         }
+        Err(e) => error!(
+            "Could not acquire the process table lock in the process-refresher thread!. Error: {}",
+            e
+        ),
     }
 }
 
-fn build_oom_event(pid: i32, cmdline: String) -> serde_json::Value {
-    let message = json!({ "cmdline": cmdline,
-                    "pid": pid.to_string(),
-                    "hostname": get_hostname(),
-                    "kernel": get_kernel_version(),
-                "time": std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis().to_string()});
-
-    return message;
+/// Keep the cache up to date from the fork/exec events, returns when the proc connector fails
+fn track_processes(
+    connector: &mut proc_connector::ProcConnector,
+    processes: &Mutex<LruCache<i32, process::ProcessInfo>>,
+    term: &AtomicBool,
+) -> Result<(), String> {
+    while !term.load(Ordering::Relaxed) {
+        match connector.read(KMSG_POLL_TIMEOUT) {
+            // the new threads share the command line of their process
+            Ok(Some(proc_connector::ProcEvent::Fork {
+                child_pid,
+                child_tgid,
+                ..
+            })) if child_pid == child_tgid => cache_process(processes, child_tgid),
+            Ok(Some(proc_connector::ProcEvent::Exec { tgid, .. })) => {
+                cache_process(processes, tgid)
+            }
+            // the exited processes stay cached, their kill is read from the kernel log afterwards
+            Ok(Some(proc_connector::ProcEvent::Exit { pid, tgid })) if pid == tgid => {
+                match processes.lock() {
+                    Ok(mut procs) => process::exited(&mut procs, tgid),
+                    Err(e) => error!(
+                        "Could not acquire the process table lock in the process-refresher thread!. Error: {}",
+                        e
+                    ),
+                }
+            }
+            Ok(_) => {}
+            Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                warn!("Some process events were lost, refreshing the whole list of processes");
+                refresh_processes(processes);
+            }
+            Err(e) => return Err(format!("Could not read the process events: {}", e)),
+        }
+    }
+
+    Ok(())
 }
 
 fn main() {
     let mut sleep_time_b = time::Duration::from_millis(5000);
-    let mut sleep_time_d = time::Duration::from_millis(10000);
-    let mut pid_max = 0;
-
-    match get_pid_max() {
-        Ok(p_max) => pid_max = p_max,
+    let mut oom_candidates = 5;
+    let mut oom_scopes: Vec<String> = incident::OomScope::ALL
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let mut routes = HashMap::new();
+    let mut kernel_rules = Vec::new();
+    let mut psi_thresholds = Vec::new();
+    let mut sleep_time_p = time::Duration::from_millis(2000);
+    let mut sleep_time_k = time::Duration::from_millis(30000);
+    let pid_max = match get_pid_max() {
+        Ok(p_max) => p_max,
         Err(e) => {
             error!("Could not determine the pid_max of the system: {}", e);
             std::process::exit(1)
         }
-    }
+    };
 
     let processes = Arc::new(Mutex::new(LruCache::new(pid_max)));
     let procs_b = Arc::clone(&processes);
     let procs_d = Arc::clone(&processes);
+    let procs_p = Arc::clone(&processes);
+    let procs_m = Arc::clone(&processes);
+    let procs_o = Arc::clone(&processes);
+    let procs_u = Arc::clone(&processes);
 
     let env = Env::default().filter_or("LOGGING_LEVEL", "info");
     env_logger::init_from_env(env);
@@ -126,7 +184,7 @@ fn main() {
                 .long("process-refresh")
                 .alias("pr")
                 .value_name("process_refresh")
-                .about("Set the frequency to refresh the list of processes in milliseconds")
+                .about("Set the frequency to refresh the list of processes in milliseconds, when the netlink proc connector is not available")
                 .takes_value(true)
                 .default_value("5000"),
         )
@@ -135,9 +193,18 @@ fn main() {
                 .long("kernel-log-refresh")
                 .alias("kr")
                 .value_name("kernel_refresh")
-                .about("Set the frequency to check for new Kernel log entries in milliseconds")
+                .about("Deprecated: the kernel log is now streamed from /dev/kmsg and this option is ignored")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("oom-candidates")
+                .long("oom-candidates")
+                .alias("oc")
+                .value_name("oom_candidates")
+                .about("Number of tasks from the oom-killer task table to report along with the killed process")
                 .takes_value(true)
-                .default_value("10000"),
+                .default_value("5"),
         )
         .arg(
             Arg::new("syslog-proto")
@@ -211,6 +278,188 @@ fn main() {
                 .takes_value(true)
                 .required(false)
         )
+        .arg(
+            Arg::new("oom-scopes")
+                .long("oom-scopes")
+                .alias("os")
+                .value_name("oom_scopes")
+                .about("Comma separated list of the OOM scopes to report. Options: global/memcg/cpuset/mempolicy")
+                .takes_value(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("route")
+                .long("route")
+                .value_name("route")
+                .about("Restrict a backend to the OOM scopes it receives. It must have the form backend=scope1,scope2 and can be repeated, backends are elasticsearch/kafka/slack/syslog")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("rule")
+                .long("rule")
+                .value_name("rule")
+                .about("Report the kernel log lines matching a rule. It can be the name of a builtin rule (segfault/general_protection/hung_task/soft_lockup/page_allocation_failure) or have the form name=regex, where the regex can capture the pid and comm named groups. It can be repeated")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("state-file")
+                .long("state-file")
+                .alias("sf")
+                .value_name("state_file")
+                .about("File where to save the position in the kernel log, so the records logged while the daemon is restarting are not lost")
+                .takes_value(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("metrics-address")
+                .long("metrics-address")
+                .value_name("metrics_address")
+                .about("Address where to serve the metrics of the daemon in the Prometheus format (e.g. 0.0.0.0:9100)")
+                .takes_value(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("psi-threshold")
+                .long("psi-threshold")
+                .value_name("psi_threshold")
+                .about("Warn when the memory pressure reaches a percentage, before the oom-killer fires. It must have the form metric=percentage where metric is some_avg10/some_avg60/full_avg10/full_avg60 and can be repeated")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("psi-cgroup")
+                .long("psi-cgroup")
+                .value_name("psi_cgroup")
+                .about("Directory of a cgroup v2 whose memory pressure is watched too (e.g. /sys/fs/cgroup/kubepods.slice). It can be repeated")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("psi-refresh")
+                .long("psi-refresh")
+                .value_name("psi_refresh")
+                .about("Set the frequency to check the memory pressure in milliseconds")
+                .takes_value(true)
+                .default_value("2000")
+        )
+        .arg(
+            Arg::new("memory-events")
+                .long("memory-events")
+                .about("Detect the OOMs from the memory.events files of the cgroup v2 hierarchy, it works even without access to /dev/kmsg")
+                .takes_value(false)
+                .required(false)
+        )
+        .arg(
+            Arg::new("cgroup-root")
+                .long("cgroup-root")
+                .value_name("cgroup_root")
+                .about("Mount point of the cgroup v2 hierarchy watched with memory-events")
+                .takes_value(true)
+                .default_value(memcg::CGROUP2_ROOT)
+        )
+        .arg(
+            Arg::new("oom-control")
+                .long("oom-control")
+                .about("Detect the OOMs from the eventfd notifications of memory.oom_control in the cgroup v1 memory hierarchy, it works even without access to /dev/kmsg")
+                .takes_value(false)
+                .required(false)
+        )
+        .arg(
+            Arg::new("cgroup-v1-root")
+                .long("cgroup-v1-root")
+                .value_name("cgroup_v1_root")
+                .about("Mount point of the cgroup v1 memory hierarchy watched with oom-control")
+                .takes_value(true)
+                .default_value(memcg::CGROUP1_MEMORY_ROOT)
+        )
+        .arg(
+            Arg::new("kubernetes-pods-url")
+                .long("kubernetes-pods-url")
+                .value_name("kubernetes_pods_url")
+                .about("URL listing the pods of the node, from the kubelet (https://127.0.0.1:10250/pods) or the API server (https://kubernetes.default.svc/api/v1/pods?fieldSelector=spec.nodeName=<node>), to add their metadata to the events of their containers")
+                .takes_value(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("kubernetes-token-file")
+                .long("kubernetes-token-file")
+                .value_name("kubernetes_token_file")
+                .about("File containing the bearer token sent to kubernetes-pods-url, the token of the service account by default for https URLs")
+                .takes_value(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("kubernetes-ca-file")
+                .long("kubernetes-ca-file")
+                .value_name("kubernetes_ca_file")
+                .about("CA certificate to verify kubernetes-pods-url, the one of the service account by default for https URLs")
+                .takes_value(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("kubernetes-insecure")
+                .long("kubernetes-insecure")
+                .about("Do not verify the certificate of kubernetes-pods-url (e.g. the self-signed one of the kubelet)")
+                .takes_value(false)
+                .required(false)
+        )
+        .arg(
+            Arg::new("kubernetes-label")
+                .long("kubernetes-label")
+                .value_name("kubernetes_label")
+                .about("Pod label to add to the events, can be repeated")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("kubernetes-refresh")
+                .long("kubernetes-refresh")
+                .value_name("kubernetes_refresh")
+                .about("Set the frequency to refresh the pods of the node in milliseconds")
+                .takes_value(true)
+                .default_value("30000"),
+        )
+        .arg(
+            Arg::new("userspace-journal")
+                .long("userspace-journal")
+                .about("Detect the kills of the userspace OOM killers (earlyoom, systemd-oomd) from their journal entries, through journalctl")
+                .takes_value(false)
+                .required(false)
+        )
+        .arg(
+            Arg::new("userspace-log")
+                .long("userspace-log")
+                .value_name("userspace_log")
+                .about("Detect the kills of the userspace OOM killers (earlyoom, systemd-oomd) from a log file they write to, can be repeated")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("backfill")
+                .long("backfill")
+                .about("Report the OOMs logged since boot before the daemon started, their events are marked as backfilled and only carry the kernel comm of the processes. It has no effect when resuming from the state file")
+                .takes_value(false)
+                .required(false)
+        )
+        .subcommand(
+            App::new("replay")
+                .about("Send the events found in a saved dmesg or /dev/kmsg dump instead of watching the kernel log")
+                .arg(
+                    Arg::new("file")
+                        .value_name("file")
+                        .about("The kernel log dump to replay")
+                        .required(true)
+                        .index(1),
+                ),
+        )
         .get_matches();
 
     if let Some(p_r) = matches.value_of("process-refresh") {
@@ -220,10 +469,41 @@ fn main() {
         }
     }
 
-    if let Some(k_r) = matches.value_of("kernel-log-refresh") {
-        match k_r.parse::<u64>() {
-            Ok(val) => sleep_time_d = time::Duration::from_millis(val),
-            Err(e) => error!("Invalid value specified for the parameter kernel-log-refresh, fallback to the default one. Error : {}", e),
+    if matches.is_present("kernel-log-refresh") {
+        warn!("The parameter kernel-log-refresh is deprecated and ignored, the kernel log is now streamed");
+    }
+
+    if let Some(o_c) = matches.value_of("oom-candidates") {
+        match o_c.parse::<usize>() {
+            Ok(val) => oom_candidates = val,
+            Err(e) => error!("Invalid value specified for the parameter oom-candidates, fallback to the default one. Error : {}", e),
+        }
+    }
+
+    if let Some(o_s) = matches.value_of("oom-scopes") {
+        match incident::OomScope::parse_list(o_s) {
+            Ok(scopes) => oom_scopes = scopes,
+            Err(e) => {
+                error!(
+                    "Invalid value specified for the parameter oom-scopes: {}",
+                    e
+                );
+                std::process::exit(1)
+            }
+        }
+    }
+
+    if let Some(r) = matches.values_of("route") {
+        for route in r {
+            match notifiers::parse_route(route) {
+                Ok((backend, scopes)) => {
+                    routes.insert(backend, scopes);
+                }
+                Err(e) => {
+                    error!("Invalid value specified for the parameter route: {}", e);
+                    std::process::exit(1)
+                }
+            }
         }
     }
 
@@ -241,210 +521,472 @@ fn main() {
     flag::register(signal_hook::consts::SIGINT, Arc::clone(&term_d))
         .expect("Could not install the SIGINT handler for the kernel-log-refresher thread");
 
-    let procs_browser = thread::spawn(move || {
-        while !term_b.load(Ordering::Relaxed) {
-            {
-                match procs_b.lock() {
-                    Ok(mut procs) => match procfs::process::all_processes() {
-                        Ok(procs_list) => {
-                            for proc in procs_list {
-                                let cmdline = match proc.cmdline() {
-                                    Ok(cmdline) => cmdline.join(" "),
-                                    Err(error) => error.to_string(),
-                                };
-
-                                debug!(
-                                    "Adding/Overwriting process {} with command line: {}",
-                                    proc.stat.pid, cmdline
-                                );
-                                procs.put(proc.stat.pid, cmdline);
-                            }
-                        }
-                        Err(e) => error!("Could not list the processes running on the host: {}", e),
-                    },
-                    Err(e) => error!(
-                        "Could not acquire the process table lock in the process-refresher thread!. Error: {}", e
-                    ),
+    let term_p = Arc::new(AtomicBool::new(false));
+    flag::register(signal_hook::consts::SIGTERM, Arc::clone(&term_p))
+        .expect("Could not install the SIGTERM handler for the memory-pressure-watcher thread");
+    flag::register(signal_hook::consts::SIGINT, Arc::clone(&term_p))
+        .expect("Could not install the SIGINT handler for the memory-pressure-watcher thread");
+
+    let term_o = Arc::new(AtomicBool::new(false));
+    flag::register(signal_hook::consts::SIGTERM, Arc::clone(&term_o))
+        .expect("Could not install the SIGTERM handler for the oom-control-watcher thread");
+    flag::register(signal_hook::consts::SIGINT, Arc::clone(&term_o))
+        .expect("Could not install the SIGINT handler for the oom-control-watcher thread");
+
+    let term_u = Arc::new(AtomicBool::new(false));
+    flag::register(signal_hook::consts::SIGTERM, Arc::clone(&term_u))
+        .expect("Could not install the SIGTERM handler for the userspace-log-watcher threads");
+    flag::register(signal_hook::consts::SIGINT, Arc::clone(&term_u))
+        .expect("Could not install the SIGINT handler for the userspace-log-watcher threads");
+
+    let term_m = Arc::new(AtomicBool::new(false));
+    flag::register(signal_hook::consts::SIGTERM, Arc::clone(&term_m))
+        .expect("Could not install the SIGTERM handler for the memory-events-watcher thread");
+    flag::register(signal_hook::consts::SIGINT, Arc::clone(&term_m))
+        .expect("Could not install the SIGINT handler for the memory-events-watcher thread");
+
+    if let Some(r) = matches.values_of("rule") {
+        for rule in r {
+            match rules::parse_rule(rule) {
+                Ok(rule) => kernel_rules.push(rule),
+                Err(e) => {
+                    error!("Invalid value specified for the parameter rule: {}", e);
+                    std::process::exit(1)
                 }
             }
-            std::thread::sleep(sleep_time_b);
         }
+    }
 
-        info!("Received termination signal. Exiting processes list refresher thread");
-    });
-
-    let dmesg_browser = thread::spawn(move || {
-        let mut syslog_proto = "";
-        let mut syslog_server = "";
-        let mut elasticsearch_server = "";
-        let mut elasticsearch_index = "";
-        let mut kafka_brokers = "";
-        let mut kafka_topic = "";
-        let mut slack_webhook = "";
-        let mut slack_channel = "";
-        let mut last_observed_timestamp = time::Duration::from_secs(0);
-
-        match get_uptime() {
-            Ok(uptime) => {
-                last_observed_timestamp = uptime;
-                info!("Machine uptime is {:#?}", last_observed_timestamp);
+    if let Some(t) = matches.values_of("psi-threshold") {
+        for threshold in t {
+            match psi::parse_threshold(threshold) {
+                Ok(threshold) => psi_thresholds.push(threshold),
+                Err(e) => {
+                    error!(
+                        "Invalid value specified for the parameter psi-threshold: {}",
+                        e
+                    );
+                    std::process::exit(1)
+                }
             }
-            Err(err) => error!("Could not determine the machine uptime: {}", err),
         }
+    }
+
+    if let Some(p_r) = matches.value_of("psi-refresh") {
+        match p_r.parse::<u64>() {
+            Ok(val) => sleep_time_p = time::Duration::from_millis(val),
+            Err(e) => error!("Invalid value specified for the parameter psi-refresh, fallback to the default one. Error : {}", e),
+        }
+    }
 
-        if let Some(s_p) = matches.value_of("syslog-proto") {
-            syslog_proto = s_p;
+    if let Some(k_r) = matches.value_of("kubernetes-refresh") {
+        match k_r.parse::<u64>() {
+            Ok(val) => sleep_time_k = time::Duration::from_millis(val),
+            Err(e) => error!("Invalid value specified for the parameter kubernetes-refresh, fallback to the default one. Error : {}", e),
         }
+    }
+
+    let pod_directory = matches.value_of("kubernetes-pods-url").map(|url| {
+        // the service account is only sent over TLS, not to a plain HTTP stand-in of the API
+        let service_account_file = |option: &str, default: &str| match matches.value_of(option) {
+            Some(file) => Some(file.to_string()),
+            None if url.starts_with("https://") && fs::metadata(default).is_ok() => {
+                Some(default.to_string())
+            }
+            None => None,
+        };
+
+        kubernetes::PodDirectory::new(
+            url.to_string(),
+            service_account_file("kubernetes-token-file", kubernetes::SERVICE_ACCOUNT_TOKEN),
+            service_account_file("kubernetes-ca-file", kubernetes::SERVICE_ACCOUNT_CA),
+            matches.is_present("kubernetes-insecure"),
+            matches
+                .values_of("kubernetes-label")
+                .map(|l| l.map(str::to_string).collect())
+                .unwrap_or_default(),
+        )
+    });
 
-        if let Some(s_s) = matches.value_of("syslog-server") {
-            syslog_server = s_s;
+    let sink = pipeline::Sink {
+        notifiers: notifiers::Notifiers {
+            syslog_proto: matches.value_of("syslog-proto").unwrap_or("").to_string(),
+            syslog_server: matches.value_of("syslog-server").unwrap_or("").to_string(),
+            elasticsearch_server: matches
+                .value_of("elasticsearch-server")
+                .unwrap_or("")
+                .to_string(),
+            elasticsearch_index: matches
+                .value_of("elasticsearch-index")
+                .unwrap_or("")
+                .to_string(),
+            kafka_brokers: matches.value_of("kafka-brokers").unwrap_or("").to_string(),
+            kafka_topic: matches.value_of("kafka-topic").unwrap_or("").to_string(),
+            slack_webhook: matches.value_of("slack-webhook").unwrap_or("").to_string(),
+            slack_channel: matches.value_of("slack-channel").unwrap_or("").to_string(),
+            routes,
+        },
+        oom_scopes,
+        kubernetes: pod_directory.clone(),
+    };
+
+    let mut psi_watcher = psi::PsiWatcher::new(
+        matches
+            .values_of("psi-cgroup")
+            .map(|c| {
+                c.map(|cgroup| cgroup.trim_end_matches('/').to_string())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        psi_thresholds,
+        procs_p,
+        oom_candidates,
+        sink.clone(),
+    );
+
+    let memory_events = matches.is_present("memory-events");
+    let cgroup_root = matches
+        .value_of("cgroup-root")
+        .unwrap_or(memcg::CGROUP2_ROOT)
+        .trim_end_matches('/')
+        .to_string();
+    let memcg_sink = sink.clone();
+    let oom_control = matches.is_present("oom-control");
+    let cgroup_v1_root = matches
+        .value_of("cgroup-v1-root")
+        .unwrap_or(memcg::CGROUP1_MEMORY_ROOT)
+        .trim_end_matches('/')
+        .to_string();
+    let oom_control_sink = sink.clone();
+    let userspace_journal = matches.is_present("userspace-journal");
+    let userspace_logs: Vec<String> = matches
+        .values_of("userspace-log")
+        .map(|l| l.map(str::to_string).collect())
+        .unwrap_or_default();
+    let userspace_sink = sink.clone();
+
+    let mut pipeline = pipeline::Pipeline {
+        processes: procs_d,
+        oom_candidates,
+        kernel_rules,
+        sink,
+        mode: pipeline::Mode::Live,
+        assembler: incident::IncidentAssembler::default(),
+        panic_announced: false,
+    };
+
+    let state_file = matches.value_of("state-file").map(str::to_string);
+    let backfill = matches.is_present("backfill");
+
+    if let Some(replay_matches) = matches.subcommand_matches("replay") {
+        let dump = replay_matches.value_of("file").unwrap(); // this is a required argument
+        pipeline.mode = pipeline::Mode::Replay;
+
+        // there is no background refresh while replaying, the pods still running are listed once
+        if let Some(Err(e)) = pod_directory.as_ref().map(|p| p.refresh()) {
+            error!("{}", e);
         }
 
-        if let Some(e_s) = matches.value_of("elasticsearch-server") {
-            elasticsearch_server = e_s;
+        match replay::replay(dump, &mut pipeline) {
+            Ok(lines) => info!("Replayed {} lines from {}", lines, dump),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1)
+            }
         }
+        return;
+    }
 
-        if let Some(e_i) = matches.value_of("elasticsearch-index") {
-            elasticsearch_index = e_i;
+    if let Some(address) = matches.value_of("metrics-address") {
+        let address = address.to_string();
+        // the thread is not joined, it lives as long as the daemon
+        thread::spawn(move || {
+            if let Err(e) = metrics::serve(&address) {
+                error!("{}", e);
+            }
+        });
+    }
+
+    if let Some(pod_directory) = pod_directory {
+        // the thread is not joined, it lives as long as the daemon
+        thread::spawn(move || loop {
+            match pod_directory.refresh() {
+                Ok(pods) => debug!("Refreshed the metadata of {} pods", pods),
+                Err(e) => error!("{}", e),
+            }
+            std::thread::sleep(sleep_time_k);
+        });
+    }
+
+    // the first snapshot is taken before reading the kernel log, which may resume from older records
+    refresh_processes(&processes);
+
+    let procs_browser = thread::spawn(move || {
+        // the events of the proc connector catch the short-lived processes the periodic scan misses
+        match proc_connector::ProcConnector::open() {
+            Ok(mut connector) => {
+                info!("Tracking the processes from the events of the netlink proc connector");
+                // the processes started while subscribing are caught by a new scan
+                refresh_processes(&procs_b);
+                match track_processes(&mut connector, &procs_b, &term_b) {
+                    Ok(()) => {
+                        info!(
+                            "Received termination signal. Exiting processes list refresher thread"
+                        );
+                        return;
+                    }
+                    Err(e) => error!("{}, falling back to scanning /proc periodically", e),
+                }
+            }
+            Err(e) => info!(
+                "The netlink proc connector is not available ({}), scanning /proc periodically",
+                e
+            ),
         }
 
-        if let Some(k_b) = matches.value_of("kafka-brokers") {
-            kafka_brokers = k_b;
+        while !term_b.load(Ordering::Relaxed) {
+            std::thread::sleep(sleep_time_b);
+            refresh_processes(&procs_b);
         }
 
-        if let Some(k_t) = matches.value_of("kafka-topic") {
-            kafka_topic = k_t;
+        info!("Received termination signal. Exiting processes list refresher thread");
+    });
+
+    // without thresholds there is nothing to warn about
+    let psi_browser = match psi_watcher.thresholds.is_empty() {
+        true => None,
+        false => Some(thread::spawn(move || {
+            while !term_p.load(Ordering::Relaxed) {
+                psi_watcher.check();
+                std::thread::sleep(sleep_time_p);
+            }
+
+            info!("Received termination signal. Exiting memory pressure watcher thread");
+        })),
+    };
+
+    let memcg_browser = match memory_events {
+        false => None,
+        true => match memcg::MemoryEventsWatcher::new(cgroup_root, procs_m, memcg_sink) {
+            Ok(mut memcg_watcher) => Some(thread::spawn(move || {
+                while !term_m.load(Ordering::Relaxed) {
+                    memcg_watcher.check(KMSG_POLL_TIMEOUT);
+                }
+
+                info!("Received termination signal. Exiting memory events watcher thread");
+            })),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1)
+            }
+        },
+    };
+
+    let oom_control_browser = match oom_control {
+        false => None,
+        true => match memcg::OomControlWatcher::new(cgroup_v1_root, procs_o, oom_control_sink) {
+            Ok(mut oom_control_watcher) => Some(thread::spawn(move || {
+                while !term_o.load(Ordering::Relaxed) {
+                    oom_control_watcher.check(KMSG_POLL_TIMEOUT);
+                }
+
+                info!("Received termination signal. Exiting oom control watcher thread");
+            })),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1)
+            }
+        },
+    };
+
+    if userspace_journal {
+        let mut detector = userspace::Detector::new(Arc::clone(&procs_u), userspace_sink.clone());
+        // the thread is not joined, journalctl is killed along with the daemon
+        thread::spawn(move || {
+            if let Err(e) = detector.follow_journal() {
+                error!(
+                    "Stopped detecting the userspace OOM kills from the journal: {}",
+                    e
+                );
+            }
+        });
+    }
+
+    let userspace_browsers: Vec<thread::JoinHandle<()>> = userspace_logs
+        .into_iter()
+        .map(|path| {
+            let mut detector =
+                userspace::Detector::new(Arc::clone(&procs_u), userspace_sink.clone());
+            let term_u = Arc::clone(&term_u);
+            thread::spawn(move || {
+                detector.follow_file(&path, &term_u);
+
+                info!(
+                    "Received termination signal. Exiting userspace log watcher thread for {}",
+                    path
+                );
+            })
+        })
+        .collect();
+
+    let dmesg_browser = thread::spawn(move || {
+        let mut last_sequence: Option<u64> = None;
+        let mut saved_oom_kills: Option<u64> = None;
+        let mut checkpoint: Option<state::Checkpoint> = None;
+
+        if let Some(path) = state_file {
+            match state::get_boot_id() {
+                Ok(boot_id) => {
+                    match state::load_for_boot(&path, &boot_id) {
+                        Ok(Some(saved)) => {
+                            info!(
+                                "Resuming the kernel log after the record {} saved in {}",
+                                saved.sequence, path
+                            );
+                            last_sequence = Some(saved.sequence);
+                            saved_oom_kills = saved.oom_kills;
+                        }
+                        Ok(None) => {}
+                        Err(e) => error!("{}", e),
+                    }
+                    checkpoint = Some(state::Checkpoint::new(path, boot_id, last_sequence));
+                }
+                Err(e) => error!("The kernel log position will not be persisted: {}", e),
+            }
         }
 
-        if let Some(s_w) = matches.value_of("slack-webhook") {
-            slack_webhook = s_w;
+        // without a saved position only the records logged from now on are of interest, unless backfilling
+        let backfilling = backfill && last_sequence.is_none();
+        if backfilling {
+            info!("Backfilling the OOMs logged since boot");
+            pipeline.mode = pipeline::Mode::Backfill;
         }
 
-        if let Some(s_c) = matches.value_of("slack-channel") {
-            slack_channel = s_c;
+        // an independent check that no kill is lost between the kernel log and the parsing
+        // the kills logged after the saved record are read again, so they are expected as well
+        let baseline = match backfilling {
+            true => Some(0),
+            false => saved_oom_kills,
+        };
+        let mut cross_check = match vmstat::read_oom_kills() {
+            Ok(sample) => Some(vmstat::KillCrossCheck::new(baseline, sample)),
+            Err(e) => {
+                info!("The detected OOM kills will not be cross-checked: {}", e);
+                None
+            }
+        };
+        // the baseline counts the kills logged before the first record to read
+        if let (Some(checkpoint), Some(cross_check)) = (checkpoint.as_mut(), cross_check.as_ref()) {
+            checkpoint.sync(cross_check.baseline(), pipeline::Pipeline::handled_kills());
         }
 
+        let mut reader = match kmsg::KmsgReader::open(kmsg::KMSG_PATH).and_then(|mut reader| {
+            if last_sequence.is_none() && !backfilling {
+                reader.seek_end()?;
+            }
+            Ok(reader)
+        }) {
+            Ok(reader) => reader,
+            Err(e) => {
+                error!(
+                    "Could not open {} to read the kernel log: {}",
+                    kmsg::KMSG_PATH,
+                    e
+                );
+                // the memory events of the cgroups are enough to detect the ooms in locked-down environments
+                if memory_events || oom_control {
+                    warn!("Detecting the OOMs only from the memory cgroups");
+                    return;
+                }
+                std::process::exit(1)
+            }
+        };
+
         while !term_d.load(Ordering::Relaxed) {
-            {
-                match procs_d.lock() {
-                    Ok(mut procs) => {
-                        let mut entries = Vec::new();
-
-                        match log_entries(Backend::Default, true) {
-                            Ok(ok_entries) => entries = ok_entries,
-                            Err(e) => error!("Could not get the log entries from the kernel ring buffer: {}", e),
-                        }
+            let handled = pipeline::Pipeline::handled_kills();
+            if let (Some(checkpoint), Some(sequence)) = (checkpoint.as_mut(), last_sequence) {
+                checkpoint.update(sequence, handled, false);
+            }
 
-                        for entry in entries {
-                            let lowercase_message = entry.message.to_lowercase();
-                            let timestamp_from_system_start = entry
-                                .timestamp_from_system_start
-                                .unwrap_or(time::Duration::from_secs(0));
+            if let Some((expected, missed)) = cross_check.as_mut().and_then(|c| c.poll(handled)) {
+                pipeline.report_missed_kills(expected, handled, missed);
+            }
 
-                            if timestamp_from_system_start <= last_observed_timestamp {
-                                debug!(
-                            "Skipping kernel log entry with timestamp from system start {:?}",
-                            timestamp_from_system_start
-                        );
-                                continue;
-                            }
-
-                            last_observed_timestamp = timestamp_from_system_start;
-                            debug!("New log entry from the kernel: {}", entry.message);
-
-                            /*
-                                Example kernel log entries we want to detect:
-                                Out of memory: Killed process 9865 (oom_trigger) total-vm:7468696kB, ... a lot more stuff ...
-                            */
-
-                            if lowercase_message.contains("out of memory:") {
-                                let mut pid_found = false;
-                                for part in lowercase_message.split_whitespace() {
-                                    if is_string_numeric(part.to_string()) {
-                                        if pid_found {
-                                            debug!("I have already found the pid for this oom event, quitting the parsing loop");
-                                            break;
-                                        }
-                                        let pid = part.to_string().parse::<i32>().unwrap(); // this is guaranteed to be a PID from the kernel log
-                                        pid_found = true;
-
-                                        match procs.get(&pid) {
-                                    Some(cmdline) => {
-                                        let full_cmdline = cmdline.clone();
-                                        procs.pop(&pid);
-                                        let oom_event = build_oom_event(pid, full_cmdline);
-                                        info!("New OOM event: {}", &oom_event);
-
-                                        if !elasticsearch_index.is_empty()
-                                            && !elasticsearch_server.is_empty()
-                                        {
-                                            match Runtime::new() {
-                                                Ok(rt) => {
-                                                    info!("Sending event to Elasticsearch");
-
-                                                    match rt.block_on(notifiers::elasticsearch_notifier(
-                                                        &oom_event,
-                                                        elasticsearch_index.to_string(),
-                                                        elasticsearch_server.to_string(),
-                                                    )) {
-                                                        Err(e) => error!("Error while sending the oom event to the configured Elasticsearch: {}", e.to_string()),
-                                                        _ => info!("OOM event successfully indexed in Elasticsearch"),
-                                                    }
-                                                },
-                                                Err(e) => error!("Could not create a tokyo runtime instance to send the event to Elasticsearch: {}", e)
-                                            }
-                                        }
-
-                                        if !slack_channel.is_empty() && !slack_webhook.is_empty() {
-                                            match Runtime::new() {
-                                                Ok(rt) => {
-                                                    info!("Sending event to Slack on channel {}", slack_channel);
-
-                                                    match rt.block_on(notifiers::slack_notifier(&oom_event, slack_webhook.to_string(), slack_channel.to_string())) {
-                                                        Err(e) => error!("Error while sending the oom event to the configured slack webhook: {}", e.to_string()),
-                                                        _ => info!("OOM event successfully delivered to Slack"),
-                                                    }
-                                                },
-                                                Err(e) => error!("Could not create a tokyo runtime instance to send the event to Slack: {}", e),
-                                            }
-                                        }
-
-                                        if !kafka_topic.is_empty() && !kafka_brokers.is_empty() {
-                                            info!("Sending event to Kafka");
-
-                                            match notifiers::kafka_notifier(&oom_event.to_string(), kafka_topic.to_string(), kafka_brokers.split(",").map(str::to_string).collect()) {
-                                                Err(e) => error!("Error while sending the oom event to the configured Kafka: {}", e.to_string()),
-                                                _ => info!("OOM event successfully delivered to Kafka"),
-                                            }
-                                        }
-
-                                        if syslog_proto == "unix"
-                                            || (!syslog_proto.is_empty()
-                                                && !syslog_server.is_empty())
-                                        {
-                                            info!("Sending event to syslog");
-                                            match notifiers::syslog_notifier(
-                                                &oom_event.to_string(),
-                                                syslog_proto.to_string(),
-                                                syslog_server.to_string(),
-                                            ) {
-                                                Err(e) => error!("Error while sending the oom event to the configured syslog: {}", e.to_string()),
-                                                _ => info!("OOM event successfully delivered to Syslog"),
-                                            }
-                                        }
-                                    }
-                                    _ => error!("Detected OOM for pid {} but could not obtain informations about the process", pid),
-                                }
-                                    }
-                                }
-                            }
+            // while backfilling the ring buffer is drained without waiting, the first empty read means it caught up
+            let timeout = match pipeline.mode {
+                pipeline::Mode::Backfill => time::Duration::from_millis(0),
+                _ => KMSG_POLL_TIMEOUT,
+            };
+
+            let entry = match reader.read(timeout) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => {
+                    // every record logged has been read, so has every kill counted until now
+                    if let Some(checkpoint) = checkpoint.as_mut() {
+                        if let Ok(oom_kills) = vmstat::read_oom_kills() {
+                            checkpoint.sync(oom_kills, pipeline::Pipeline::handled_kills());
                         }
                     }
-                    Err(e) => error!("Could not acquire the process table lock in the process-refresher thread!. Error: {}", e),
+
+                    if pipeline.mode == pipeline::Mode::Backfill {
+                        info!("Backfill completed, watching the kernel log for new OOMs");
+                        pipeline.mode = pipeline::Mode::Live;
+                    }
+                    continue;
+                }
+                Err(e) if e.raw_os_error() == Some(libc::EPIPE) => {
+                    // the reader moved to the oldest record left, the gap is reported once it is read
+                    warn!("The kernel ring buffer wrapped before its records could be read");
+                    metrics::inc(&metrics::KERNEL_LOG_OVERRUNS, 1);
+                    continue;
                 }
+                Err(e) => {
+                    error!(
+                        "Could not get the log entries from the kernel ring buffer: {}",
+                        e
+                    );
+                    std::thread::sleep(KMSG_POLL_TIMEOUT);
+
+                    // a fresh reader starts from the oldest record, the ones already seen are skipped below;
+                    // before the first record is read there are none to skip, so it starts from the end as at startup
+                    match kmsg::KmsgReader::open(kmsg::KMSG_PATH).and_then(|mut new_reader| {
+                        if last_sequence.is_none() && pipeline.mode != pipeline::Mode::Backfill {
+                            new_reader.seek_end()?;
+                        }
+                        Ok(new_reader)
+                    }) {
+                        Ok(new_reader) => reader = new_reader,
+                        Err(e) => error!("Could not reopen {}: {}", kmsg::KMSG_PATH, e),
+                    }
+                    continue;
+                }
+            };
+
+            let continuity = kmsg::continuity(last_sequence, entry.sequence);
+            if continuity == kmsg::Continuity::Duplicate {
+                debug!(
+                    "Skipping kernel log entry with sequence number {}",
+                    entry.sequence
+                );
+                continue;
+            }
+
+            metrics::inc(&metrics::KERNEL_LOG_RECORDS_READ, 1);
+            if let kmsg::Continuity::Gap { first, last } = continuity {
+                pipeline.report_dropped(first, last);
+            }
+
+            last_sequence = Some(entry.sequence);
+            debug!("New log entry from the kernel: {}", entry.message);
+
+            // multi-line messages (like the Mem-Info dump) are logged as a single record
+            for line in entry.message.lines() {
+                pipeline.process_line(line, entry.timestamp_from_system_start);
             }
+        }
 
-            std::thread::sleep(sleep_time_d);
+        if let (Some(checkpoint), Some(sequence)) = (checkpoint.as_mut(), last_sequence) {
+            checkpoint.update(sequence, pipeline::Pipeline::handled_kills(), true);
         }
 
         info!("Received termination signal. Exiting kernel log refresher thread");
@@ -456,4 +998,24 @@ fn main() {
     dmesg_browser
         .join()
         .expect("Could not join() the kernel-log-refresher thread");
+    for userspace_browser in userspace_browsers {
+        userspace_browser
+            .join()
+            .expect("Could not join() a userspace-log-watcher thread");
+    }
+    if let Some(oom_control_browser) = oom_control_browser {
+        oom_control_browser
+            .join()
+            .expect("Could not join() the oom-control-watcher thread");
+    }
+    if let Some(memcg_browser) = memcg_browser {
+        memcg_browser
+            .join()
+            .expect("Could not join() the memory-events-watcher thread");
+    }
+    if let Some(psi_browser) = psi_browser {
+        psi_browser
+            .join()
+            .expect("Could not join() the memory-pressure-watcher thread");
+    }
 }
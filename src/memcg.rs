@@ -0,0 +1,962 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use lru::LruCache;
+
+use crate::events;
+use crate::incident;
+use crate::pipeline;
+use crate::process;
+
+/*
+    Every cgroup v2 counts its memory events in memory.events, e.g.:
+    low 0
+    high 0
+    max 1523
+    oom 2
+    oom_kill 1
+    oom_group_kill 0
+    The counters of memory.events include the descendants of the cgroup, memory.events.local (kernel 5.2+)
+    only counts the cgroup itself and is preferred, so an oom is not reported again by every ancestor.
+*/
+
+pub const CGROUP2_ROOT: &str = "/sys/fs/cgroup";
+
+const OOM_COUNTERS: &[&str] = &["oom", "oom_kill", "oom_group_kill"];
+
+// the members of a cgroup that exited this long before its kill is noticed may be the victim
+const RECENT_EXIT_MS: u64 = 5000;
+
+// the flag of the tasks being torn down, in the 9th field of /proc/<pid>/stat
+const PF_EXITING: u32 = 0x0000_0004;
+// SIGKILL in the ShdPnd mask of /proc/<pid>/status, the oom-killer sends it to the whole process
+const SIGKILL_MASK: u64 = 1 << (libc::SIGKILL - 1);
+
+const INOTIFY_BUFFER_SIZE: usize = 64 * 1024;
+
+pub fn parse_memory_events(content: &str) -> BTreeMap<String, u64> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter_map(|(counter, value)| {
+            value
+                .trim()
+                .parse::<u64>()
+                .ok()
+                .map(|value| (counter.to_string(), value))
+        })
+        .collect()
+}
+
+struct Inotify {
+    fd: libc::c_int,
+    buffer: Vec<u8>,
+}
+
+impl Inotify {
+    fn init() -> io::Result<Inotify> {
+        match unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Inotify {
+                fd,
+                buffer: vec![0; INOTIFY_BUFFER_SIZE],
+            }),
+        }
+    }
+
+    fn add_watch(&self, path: &Path, mask: u32) -> io::Result<i32> {
+        let c_path = match CString::new(path.to_string_lossy().as_bytes()) {
+            Ok(c_path) => c_path,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+        };
+
+        match unsafe { libc::inotify_add_watch(self.fd, c_path.as_ptr(), mask) } {
+            -1 => Err(io::Error::last_os_error()),
+            wd => Ok(wd),
+        }
+    }
+
+    /// Wait up to timeout for events, every one is returned as (watch descriptor, mask, name)
+    fn read(&mut self, timeout: time::Duration) -> io::Result<Vec<(i32, u32, String)>> {
+        let mut fds = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) } {
+            -1 => {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    return Ok(Vec::new());
+                }
+                return Err(e);
+            }
+            0 => return Ok(Vec::new()),
+            _ => {}
+        }
+
+        let size = match unsafe {
+            libc::read(
+                self.fd,
+                self.buffer.as_mut_ptr() as *mut libc::c_void,
+                self.buffer.len(),
+            )
+        } {
+            -1 => {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted {
+                    return Ok(Vec::new());
+                }
+                return Err(e);
+            }
+            size => size as usize,
+        };
+
+        // struct inotify_event { int wd; uint32_t mask; uint32_t cookie; uint32_t len; char name[]; }
+        let mut inotify_events = Vec::new();
+        let mut offset = 0;
+        while offset + 16 <= size {
+            let field = |i: usize| {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(&self.buffer[offset + i..offset + i + 4]);
+                bytes
+            };
+            let wd = i32::from_ne_bytes(field(0));
+            let mask = u32::from_ne_bytes(field(4));
+            let len = u32::from_ne_bytes(field(12)) as usize;
+
+            let name = &self.buffer[offset + 16..(offset + 16 + len).min(size)];
+            let name_end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+            inotify_events.push((
+                wd,
+                mask,
+                String::from_utf8_lossy(&name[..name_end]).to_string(),
+            ));
+
+            offset += 16 + len;
+        }
+
+        Ok(inotify_events)
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+// what /proc tells about a member of a cgroup while it is still there
+struct TaskState {
+    flags: u32,
+    state: char,
+    sigkill_pending: bool,
+    rss: i64,
+}
+
+impl TaskState {
+    fn read(pid: i32) -> Option<TaskState> {
+        let process = procfs::process::Process::new(pid).ok()?;
+        Some(TaskState {
+            flags: process.stat.flags,
+            state: process.stat.state,
+            sigkill_pending: process.status().is_ok_and(|s| s.shdpnd & SIGKILL_MASK != 0),
+            rss: process.stat.rss,
+        })
+    }
+
+    // a killed process has a SIGKILL pending, then is exiting, then is a zombie until its parent reaps it
+    fn dying(&self) -> bool {
+        self.flags & PF_EXITING != 0
+            || self.state == 'Z'
+            || self.state == 'X'
+            || self.sigkill_pending
+    }
+}
+
+/// The oom-killer mostly picks the biggest task. The victim is dying or gone when the oom is noticed, so the cached
+/// members of the cgroup being killed come first, then the last ones that exited, then the biggest of the live ones.
+fn likely_victim(
+    processes: &Mutex<LruCache<i32, process::ProcessInfo>>,
+    root: &str,
+    cgroup: &str,
+) -> Option<serde_json::Value> {
+    let path = match cgroup.strip_prefix(root.trim_end_matches('/'))? {
+        "" => "/",
+        path => path,
+    };
+
+    let members: Vec<(i32, process::ProcessInfo)> = match processes.lock() {
+        Ok(procs) => procs
+            .iter()
+            .filter(|(_, p)| p.cgroup.as_deref() == Some(path))
+            .map(|(pid, p)| (*pid, p.clone()))
+            .collect(),
+        Err(e) => {
+            error!("Could not acquire the process table lock in the memory-cgroup-watcher thread!. Error: {}", e);
+            return None;
+        }
+    };
+
+    let now = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|t| t.as_millis() as u64)
+        .unwrap_or(0);
+    pick_victim(
+        members
+            .into_iter()
+            .map(|(pid, member)| (pid, member, TaskState::read(pid)))
+            .collect(),
+        now,
+    )
+}
+
+/// The likely victim among the members of a cgroup, with their state when they are still there
+fn pick_victim(
+    members: Vec<(i32, process::ProcessInfo, Option<TaskState>)>,
+    now: u64,
+) -> Option<serde_json::Value> {
+    let (pid, member, exiting, _, rss) = members
+        .into_iter()
+        .filter_map(|(pid, member, task)| {
+            let dying = task.as_ref().is_some_and(TaskState::dying);
+            // gone before its exit was tracked, it has just exited
+            let exiting = dying || (task.is_none() && member.exit_time.is_none());
+            let exited = member
+                .exit_time
+                .filter(|t| now.saturating_sub(*t) < RECENT_EXIT_MS);
+            match (task, exiting, exited) {
+                (None, false, None) => None,
+                (task, exiting, exited) => Some((
+                    pid,
+                    member,
+                    exiting,
+                    exited,
+                    task.map(|t| t.rss).unwrap_or(0),
+                )),
+            }
+        })
+        .max_by_key(|(_, _, exiting, exited, rss)| (*exiting, *exited, *rss))?;
+
+    let mut victim = events::build_consumer(pid, &member.comm, rss, Some(&member.cmdline));
+    victim["exited"] = serde_json::Value::Bool(exiting || member.exit_time.is_some());
+    Some(victim)
+}
+
+enum Watched {
+    Directory(String),
+    Events(String),
+}
+
+pub struct MemoryEventsWatcher {
+    pub root: String,
+    pub sink: pipeline::Sink,
+    inotify: Inotify,
+    watches: HashMap<i32, Watched>,
+    counters: HashMap<String, BTreeMap<String, u64>>,
+    processes: Arc<Mutex<LruCache<i32, process::ProcessInfo>>>,
+}
+
+impl MemoryEventsWatcher {
+    pub fn new(
+        root: String,
+        processes: Arc<Mutex<LruCache<i32, process::ProcessInfo>>>,
+        sink: pipeline::Sink,
+    ) -> Result<MemoryEventsWatcher, String> {
+        let inotify = match Inotify::init() {
+            Ok(inotify) => inotify,
+            Err(e) => return Err(format!("Could not initialize inotify: {}", e)),
+        };
+
+        let mut watcher = MemoryEventsWatcher {
+            root,
+            sink,
+            inotify,
+            watches: HashMap::new(),
+            counters: HashMap::new(),
+            processes,
+        };
+
+        let root = watcher.root.clone();
+        watcher.watch_tree(Path::new(&root));
+        if watcher.counters.is_empty() {
+            return Err(format!(
+                "No memory.events file found under {}, is it a cgroup v2 hierarchy with the memory controller?",
+                root
+            ));
+        }
+        info!(
+            "Watching the memory events of {} cgroups under {}",
+            watcher.counters.len(),
+            root
+        );
+
+        Ok(watcher)
+    }
+
+    fn watch_events(&mut self, directory: &Path) {
+        let dir_path = directory.to_string_lossy().to_string();
+        if self.counters.contains_key(&dir_path) {
+            return;
+        }
+
+        let events_path = match ["memory.events.local", "memory.events"]
+            .iter()
+            .map(|file| directory.join(file))
+            .find(|path| path.exists())
+        {
+            Some(events_path) => events_path,
+            None => return,
+        };
+
+        match self.inotify.add_watch(&events_path, libc::IN_MODIFY) {
+            Ok(wd) => {
+                let counters = fs::read_to_string(&events_path)
+                    .map(|content| parse_memory_events(&content))
+                    .unwrap_or_default();
+                self.counters.insert(dir_path.clone(), counters);
+                self.watches.insert(wd, Watched::Events(dir_path));
+            }
+            Err(e) => debug!("Could not watch {}: {}", events_path.display(), e),
+        }
+    }
+
+    fn watch_tree(&mut self, directory: &Path) {
+        let dir_path = directory.to_string_lossy().to_string();
+
+        // new child cgroups are watched as soon as they are created
+        match self
+            .inotify
+            .add_watch(directory, libc::IN_CREATE | libc::IN_ONLYDIR)
+        {
+            Ok(wd) => {
+                self.watches.insert(wd, Watched::Directory(dir_path));
+            }
+            Err(e) => debug!("Could not watch the cgroup {}: {}", dir_path, e),
+        }
+
+        self.watch_events(directory);
+
+        if let Ok(entries) = fs::read_dir(directory) {
+            for entry in entries.flatten() {
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    self.watch_tree(&entry.path());
+                }
+            }
+        }
+    }
+
+    /// Wait up to timeout for changes of the memory events and report the new ooms
+    pub fn check(&mut self, timeout: time::Duration) {
+        let inotify_events = match self.inotify.read(timeout) {
+            Ok(inotify_events) => inotify_events,
+            Err(e) => {
+                error!("Could not read the inotify events of the cgroups: {}", e);
+                std::thread::sleep(timeout);
+                return;
+            }
+        };
+
+        for (wd, mask, name) in inotify_events {
+            if mask & libc::IN_IGNORED != 0 {
+                // the cgroup was removed
+                if let Some(Watched::Events(cgroup)) = self.watches.remove(&wd) {
+                    self.counters.remove(&cgroup);
+                }
+                continue;
+            }
+
+            match self.watches.get(&wd) {
+                Some(Watched::Directory(parent)) if mask & libc::IN_ISDIR != 0 => {
+                    let directory = Path::new(parent).join(name);
+                    self.watch_tree(&directory);
+                }
+                // the files of a controller appear once it is enabled in the parent cgroup
+                Some(Watched::Directory(parent)) if name.starts_with("memory.events") => {
+                    let directory = Path::new(parent).to_path_buf();
+                    self.watch_events(&directory);
+                }
+                Some(Watched::Events(cgroup)) => {
+                    let cgroup = cgroup.clone();
+                    self.check_cgroup(&cgroup);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn check_cgroup(&mut self, cgroup: &str) {
+        let events_path = ["memory.events.local", "memory.events"]
+            .iter()
+            .map(|file| Path::new(cgroup).join(file))
+            .find(|path| path.exists());
+        let counters = match events_path.map(fs::read_to_string) {
+            Some(Ok(content)) => parse_memory_events(&content),
+            Some(Err(e)) => {
+                debug!("Could not read the memory events of {}: {}", cgroup, e);
+                return;
+            }
+            None => return,
+        };
+
+        let previous = self
+            .counters
+            .insert(cgroup.to_string(), counters.clone())
+            .unwrap_or_default();
+
+        if !self.sink.in_scope(incident::OomScope::Memcg.as_str()) {
+            return;
+        }
+
+        for counter in OOM_COUNTERS {
+            let count = counters.get(*counter).copied().unwrap_or(0);
+            let previous_count = previous.get(*counter).copied().unwrap_or(0);
+            if count <= previous_count {
+                continue;
+            }
+
+            let victim = match *counter {
+                "oom" => None,
+                _ => likely_victim(&self.processes, &self.root, cgroup),
+            };
+            let cgroup_event = events::build_cgroup_oom_event(
+                counter,
+                count - previous_count,
+                "memory.events",
+                cgroup.strip_prefix(&self.root).unwrap_or(cgroup),
+                &counters,
+                victim,
+            );
+            info!("New cgroup memory event: {}", &cgroup_event);
+            self.sink.emit(&cgroup_event);
+        }
+    }
+}
+
+/*
+    Memory cgroups v1 notify their ooms through an eventfd registered in cgroup.event_control, along with
+    an open memory.oom_control file. The counters are then read from memory.oom_control:
+    oom_kill_disable 0
+    under_oom 0
+    oom_kill 3
+    The notification comes before the kill and is sent to the descendants of the cgroup too.
+*/
+
+pub const CGROUP1_MEMORY_ROOT: &str = "/sys/fs/cgroup/memory";
+
+// the kill follows the notification shortly, the oom_kill counter is watched for this long
+const OOM_KILL_WAIT: time::Duration = time::Duration::from_secs(2);
+const OOM_KILL_POLL_TIMEOUT: time::Duration = time::Duration::from_millis(100);
+
+struct Registration {
+    eventfd: fs::File,
+    // the kernel drops the registration when this file is closed
+    _oom_control: fs::File,
+    oom_kills: u64,
+}
+
+pub struct OomControlWatcher {
+    pub root: String,
+    pub sink: pipeline::Sink,
+    inotify: Inotify,
+    directories: HashMap<i32, String>,
+    registrations: HashMap<String, Registration>,
+    // cgroups under oom whose kill is not seen yet, with the time of their notification
+    pending_kills: HashMap<String, time::Instant>,
+    processes: Arc<Mutex<LruCache<i32, process::ProcessInfo>>>,
+}
+
+/// The counters of memory.oom_control, with its oom_kill counter that kernels older than 4.13 do not have
+fn parse_oom_control(content: &str) -> (BTreeMap<String, u64>, Option<u64>) {
+    let counters = parse_memory_events(content);
+    let oom_kills = counters.get("oom_kill").copied();
+    (counters, oom_kills)
+}
+
+fn read_oom_control(cgroup: &str) -> io::Result<(BTreeMap<String, u64>, Option<u64>)> {
+    fs::read_to_string(format!("{}/memory.oom_control", cgroup))
+        .map(|content| parse_oom_control(&content))
+}
+
+// cgroups v1 count a kill only in the cgroup of the victim, that may be a descendant of the one under oom
+fn subtree<'a>(cgroups: impl Iterator<Item = &'a String>, cgroup: &str) -> Vec<String> {
+    let descendants = format!("{}/", cgroup);
+    cgroups
+        .filter(|c| *c == cgroup || c.starts_with(&descendants))
+        .cloned()
+        .collect()
+}
+
+impl OomControlWatcher {
+    pub fn new(
+        root: String,
+        processes: Arc<Mutex<LruCache<i32, process::ProcessInfo>>>,
+        sink: pipeline::Sink,
+    ) -> Result<OomControlWatcher, String> {
+        let inotify = match Inotify::init() {
+            Ok(inotify) => inotify,
+            Err(e) => return Err(format!("Could not initialize inotify: {}", e)),
+        };
+
+        let mut watcher = OomControlWatcher {
+            root,
+            sink,
+            inotify,
+            directories: HashMap::new(),
+            registrations: HashMap::new(),
+            pending_kills: HashMap::new(),
+            processes,
+        };
+
+        let root = watcher.root.clone();
+        watcher.watch_tree(Path::new(&root));
+        if watcher.registrations.is_empty() {
+            return Err(format!(
+                "Could not register to the oom notifications of any cgroup under {}, is it a cgroup v1 memory hierarchy?",
+                root
+            ));
+        }
+        info!(
+            "Watching the oom notifications of {} cgroups under {}",
+            watcher.registrations.len(),
+            root
+        );
+
+        Ok(watcher)
+    }
+
+    fn register(&mut self, cgroup: &str) -> io::Result<()> {
+        let oom_control = fs::File::open(format!("{}/memory.oom_control", cgroup))?;
+        let eventfd = match unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) } {
+            -1 => return Err(io::Error::last_os_error()),
+            fd => unsafe { fs::File::from_raw_fd(fd) },
+        };
+
+        fs::write(
+            format!("{}/cgroup.event_control", cgroup),
+            format!("{} {}", eventfd.as_raw_fd(), oom_control.as_raw_fd()),
+        )?;
+
+        let (_, oom_kills) = read_oom_control(cgroup)?;
+        let oom_kills = oom_kills.unwrap_or(0);
+        self.registrations.insert(
+            cgroup.to_string(),
+            Registration {
+                eventfd,
+                _oom_control: oom_control,
+                oom_kills,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn watch_tree(&mut self, directory: &Path) {
+        let dir_path = directory.to_string_lossy().to_string();
+
+        // containers come and go, their cgroups are registered as soon as they are created
+        match self
+            .inotify
+            .add_watch(directory, libc::IN_CREATE | libc::IN_ONLYDIR)
+        {
+            Ok(wd) => {
+                self.directories.insert(wd, dir_path.clone());
+            }
+            Err(e) => debug!("Could not watch the cgroup {}: {}", dir_path, e),
+        }
+
+        if let Err(e) = self.register(&dir_path) {
+            debug!(
+                "Could not register to the oom notifications of {}: {}",
+                dir_path, e
+            );
+        }
+
+        if let Ok(entries) = fs::read_dir(directory) {
+            for entry in entries.flatten() {
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    self.watch_tree(&entry.path());
+                }
+            }
+        }
+    }
+
+    /// Wait up to timeout for oom notifications and report them
+    pub fn check(&mut self, timeout: time::Duration) {
+        self.check_pending_kills();
+
+        let cgroups: Vec<String> = self.registrations.keys().cloned().collect();
+        let mut fds: Vec<libc::pollfd> = std::iter::once(self.inotify.fd)
+            .chain(
+                cgroups
+                    .iter()
+                    .map(|c| self.registrations[c].eventfd.as_raw_fd()),
+            )
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+
+        let timeout = match self.pending_kills.is_empty() {
+            true => timeout,
+            false => OOM_KILL_POLL_TIMEOUT,
+        };
+        match unsafe {
+            libc::poll(
+                fds.as_mut_ptr(),
+                fds.len() as libc::nfds_t,
+                timeout.as_millis() as libc::c_int,
+            )
+        } {
+            -1 => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    error!(
+                        "Could not wait for the oom notifications of the cgroups: {}",
+                        e
+                    );
+                    std::thread::sleep(timeout);
+                }
+                return;
+            }
+            0 => return,
+            _ => {}
+        }
+
+        let notified: Vec<String> = cgroups
+            .into_iter()
+            .zip(fds[1..].iter())
+            .filter(|(_, fd)| fd.revents & libc::POLLIN != 0)
+            .map(|(cgroup, _)| cgroup)
+            .filter(|cgroup| self.consume_notification(cgroup))
+            .collect();
+
+        // the descendants of the cgroup under oom are notified too, only the topmost one is reported
+        for cgroup in notified.iter().filter(|cgroup| {
+            !notified
+                .iter()
+                .any(|other| cgroup.starts_with(&format!("{}/", other)))
+        }) {
+            self.report_oom(cgroup);
+        }
+
+        if fds[0].revents & libc::POLLIN != 0 {
+            self.check_directories();
+        }
+    }
+
+    /// Drain the eventfd of the cgroup, false is returned if the notification was for its removal
+    fn consume_notification(&mut self, cgroup: &str) -> bool {
+        if let Some(registration) = self.registrations.get_mut(cgroup) {
+            let mut counter = [0; 8];
+            let _ = registration.eventfd.read(&mut counter);
+        }
+
+        if !Path::new(cgroup).exists() {
+            self.registrations.remove(cgroup);
+            self.pending_kills.remove(cgroup);
+            return false;
+        }
+
+        true
+    }
+
+    fn check_directories(&mut self) {
+        let inotify_events = match self.inotify.read(time::Duration::from_millis(0)) {
+            Ok(inotify_events) => inotify_events,
+            Err(e) => {
+                error!("Could not read the inotify events of the cgroups: {}", e);
+                return;
+            }
+        };
+
+        for (wd, mask, name) in inotify_events {
+            if mask & libc::IN_IGNORED != 0 {
+                self.directories.remove(&wd);
+                continue;
+            }
+
+            if mask & libc::IN_ISDIR != 0 {
+                if let Some(parent) = self.directories.get(&wd) {
+                    let directory = Path::new(parent).join(name);
+                    self.watch_tree(&directory);
+                }
+            }
+        }
+    }
+
+    fn report_oom(&mut self, cgroup: &str) {
+        if !self.sink.in_scope(incident::OomScope::Memcg.as_str()) {
+            return;
+        }
+
+        let (counters, oom_kills) = read_oom_control(cgroup).unwrap_or_default();
+        let cgroup_event = events::build_cgroup_oom_event(
+            "oom",
+            1,
+            "memory.oom_control",
+            cgroup.strip_prefix(&self.root).unwrap_or(cgroup),
+            &counters,
+            None,
+        );
+        info!("New cgroup memory event: {}", &cgroup_event);
+        self.sink.emit(&cgroup_event);
+
+        // kernels older than 4.13 do not count the kills
+        if oom_kills.is_some() {
+            self.pending_kills
+                .insert(cgroup.to_string(), time::Instant::now());
+        }
+    }
+
+    fn check_pending_kills(&mut self) {
+        let pending: Vec<(String, time::Instant)> = self.pending_kills.drain().collect();
+
+        for (cgroup, notified_at) in pending {
+            let mut killed = false;
+            for victim_cgroup in subtree(self.registrations.keys(), &cgroup) {
+                let (counters, oom_kills) = match read_oom_control(&victim_cgroup) {
+                    Ok((counters, oom_kills)) => (counters, oom_kills.unwrap_or(0)),
+                    Err(_) => continue,
+                };
+                let previous_oom_kills = match self.registrations.get_mut(&victim_cgroup) {
+                    Some(registration) => std::mem::replace(&mut registration.oom_kills, oom_kills),
+                    None => continue,
+                };
+                if oom_kills <= previous_oom_kills {
+                    continue;
+                }
+
+                killed = true;
+                let cgroup_event = events::build_cgroup_oom_event(
+                    "oom_kill",
+                    oom_kills - previous_oom_kills,
+                    "memory.oom_control",
+                    victim_cgroup
+                        .strip_prefix(&self.root)
+                        .unwrap_or(&victim_cgroup),
+                    &counters,
+                    likely_victim(&self.processes, &self.root, &victim_cgroup),
+                );
+                info!("New cgroup memory event: {}", &cgroup_event);
+                self.sink.emit(&cgroup_event);
+            }
+
+            // the oom may also be resolved without killing anything (e.g. by reclaiming page cache)
+            if !killed && notified_at.elapsed() < OOM_KILL_WAIT {
+                self.pending_kills.insert(cgroup, notified_at);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_625_487_100_000;
+
+    #[test]
+    fn memory_events() {
+        let content = "low 0\nhigh 0\nmax 1523\noom 2\noom_kill 1\noom_group_kill 0\n";
+        let counters: BTreeMap<String, u64> = [
+            ("low", 0),
+            ("high", 0),
+            ("max", 1523),
+            ("oom", 2),
+            ("oom_kill", 1),
+            ("oom_group_kill", 0),
+        ]
+        .iter()
+        .map(|(counter, value)| (counter.to_string(), *value))
+        .collect();
+        assert_eq!(parse_memory_events(content), counters);
+
+        // kernels before 5.17 have no oom_group_kill, the malformed lines are skipped
+        let content = "low 0\nhigh 0\nmax 3\noom 1\noom_kill 1\nbroken\nswap -1\n";
+        let counters = parse_memory_events(content);
+        assert_eq!(counters.get("oom_kill"), Some(&1));
+        assert_eq!(counters.get("oom_group_kill"), None);
+        assert_eq!(counters.len(), 5);
+        assert!(parse_memory_events("").is_empty());
+    }
+
+    #[test]
+    fn oom_control() {
+        // kernel 4.13+
+        let (counters, oom_kills) =
+            parse_oom_control("oom_kill_disable 0\nunder_oom 1\noom_kill 3\n");
+        assert_eq!(oom_kills, Some(3));
+        assert_eq!(counters.get("under_oom"), Some(&1));
+        assert_eq!(counters.len(), 3);
+
+        // older kernels
+        let (counters, oom_kills) = parse_oom_control("oom_kill_disable 0\nunder_oom 0\n");
+        assert_eq!(oom_kills, None);
+        assert_eq!(counters.len(), 2);
+    }
+
+    #[test]
+    fn kill_subtree() {
+        let root = "/sys/fs/cgroup/memory/kubepods";
+        let registered: Vec<String> = [
+            "",
+            "/pod1",
+            "/pod1/container1",
+            "/pod1/container1/nested",
+            "/pod1/container2",
+            "/pod10",
+            "/pod10/container1",
+        ]
+        .iter()
+        .map(|cgroup| format!("{}{}", root, cgroup))
+        .collect();
+        let subtree_of = |cgroup: &str| {
+            let mut cgroups: Vec<String> =
+                subtree(registered.iter(), &format!("{}{}", root, cgroup))
+                    .iter()
+                    .map(|c| c.trim_start_matches(root).to_string())
+                    .collect();
+            cgroups.sort();
+            cgroups
+        };
+
+        assert_eq!(
+            subtree_of("/pod1"),
+            vec![
+                "/pod1",
+                "/pod1/container1",
+                "/pod1/container1/nested",
+                "/pod1/container2"
+            ]
+        );
+        assert_eq!(subtree_of("/pod1/container2"), vec!["/pod1/container2"]);
+        assert_eq!(subtree_of("").len(), registered.len());
+        assert!(subtree_of("/pod2").is_empty());
+    }
+
+    fn member(comm: &str, exit_time: Option<u64>) -> process::ProcessInfo {
+        process::ProcessInfo {
+            cmdline: format!("/usr/bin/{}", comm),
+            exe: None,
+            cwd: None,
+            uid: 1000,
+            user: None,
+            ppid: 1,
+            start_time: Some(NOW - 3_600_000),
+            comm: comm.to_string(),
+            cgroup: Some("/kubepods.slice/pod1".to_string()),
+            container: None,
+            exit_time,
+        }
+    }
+
+    fn running(rss: i64) -> Option<TaskState> {
+        Some(TaskState {
+            flags: 0x0040_0100,
+            state: 'S',
+            sigkill_pending: false,
+            rss,
+        })
+    }
+
+    // the pid and the exited flag of the victim picked
+    fn victim(
+        members: Vec<(i32, process::ProcessInfo, Option<TaskState>)>,
+    ) -> Option<(String, bool)> {
+        pick_victim(members, NOW).map(|victim| {
+            (
+                victim["pid"].as_str().unwrap().to_string(),
+                victim["exited"].as_bool().unwrap(),
+            )
+        })
+    }
+
+    #[test]
+    fn victims() {
+        let live = || (100, member("nginx", None), running(90_000));
+        let cases = vec![
+            (vec![], None),
+            // the biggest of the live ones
+            (
+                vec![live(), (101, member("sidecar", None), running(2_000))],
+                Some(("100", false)),
+            ),
+            (
+                vec![
+                    live(),
+                    (
+                        101,
+                        member("java", None),
+                        Some(TaskState {
+                            flags: 0x0040_0104,
+                            ..running(2_000).unwrap()
+                        }),
+                    ),
+                ],
+                Some(("101", true)),
+            ),
+            (
+                vec![
+                    live(),
+                    (
+                        101,
+                        member("java", None),
+                        Some(TaskState {
+                            state: 'Z',
+                            ..running(0).unwrap()
+                        }),
+                    ),
+                ],
+                Some(("101", true)),
+            ),
+            (
+                vec![
+                    live(),
+                    (
+                        101,
+                        member("java", None),
+                        Some(TaskState {
+                            sigkill_pending: true,
+                            ..running(2_000).unwrap()
+                        }),
+                    ),
+                ],
+                Some(("101", true)),
+            ),
+            // gone before the proc connector or a refresh noticed it
+            (
+                vec![live(), (101, member("java", None), None)],
+                Some(("101", true)),
+            ),
+            // exited just before the oom was noticed
+            (
+                vec![live(), (101, member("java", Some(NOW - 1_000)), None)],
+                Some(("101", true)),
+            ),
+            // exited long before
+            (
+                vec![live(), (101, member("java", Some(NOW - 60_000)), None)],
+                Some(("100", false)),
+            ),
+            (vec![(101, member("java", Some(NOW - 60_000)), None)], None),
+        ];
+
+        for (i, (members, expected)) in cases.into_iter().enumerate() {
+            assert_eq!(
+                victim(members),
+                expected.map(|(pid, exited)| (pid.to_string(), exited)),
+                "case {}",
+                i
+            );
+        }
+    }
+}
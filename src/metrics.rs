@@ -0,0 +1,124 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/*
+    Counters of the daemon itself, exposed in the Prometheus text format:
+    # TYPE oom_notifier_kernel_log_records_dropped_total counter
+    oom_notifier_kernel_log_records_dropped_total 42
+*/
+
+pub static KERNEL_LOG_RECORDS_READ: AtomicU64 = AtomicU64::new(0);
+pub static KERNEL_LOG_RECORDS_DROPPED: AtomicU64 = AtomicU64::new(0);
+pub static KERNEL_LOG_OVERRUNS: AtomicU64 = AtomicU64::new(0);
+pub static EVENTS_EMITTED: AtomicU64 = AtomicU64::new(0);
+pub static OOM_KILLS_DETECTED: AtomicU64 = AtomicU64::new(0);
+pub static OOM_KILLS_REPORTED: AtomicU64 = AtomicU64::new(0);
+pub static OOM_KILLS_FILTERED: AtomicU64 = AtomicU64::new(0);
+pub static OOM_KILLS_MISSED: AtomicU64 = AtomicU64::new(0);
+
+const COUNTERS: &[(&str, &str, &AtomicU64)] = &[
+    (
+        "oom_notifier_kernel_log_records_read_total",
+        "Records read from the kernel log",
+        &KERNEL_LOG_RECORDS_READ,
+    ),
+    (
+        "oom_notifier_kernel_log_records_dropped_total",
+        "Records lost before they could be read from the kernel log",
+        &KERNEL_LOG_RECORDS_DROPPED,
+    ),
+    (
+        "oom_notifier_kernel_log_overruns_total",
+        "Times the kernel ring buffer wrapped before its records could be read",
+        &KERNEL_LOG_OVERRUNS,
+    ),
+    (
+        "oom_notifier_oom_kills_detected_total",
+        "OOM kills found in the kernel log",
+        &OOM_KILLS_DETECTED,
+    ),
+    (
+        "oom_notifier_oom_kills_reported_total",
+        "OOM kills sent to the notifiers",
+        &OOM_KILLS_REPORTED,
+    ),
+    (
+        "oom_notifier_oom_kills_filtered_total",
+        "OOM kills not reported since their scope is filtered out",
+        &OOM_KILLS_FILTERED,
+    ),
+    (
+        "oom_notifier_oom_kills_missed_total",
+        "OOM kills counted by the kernel in /proc/vmstat but neither reported nor filtered out",
+        &OOM_KILLS_MISSED,
+    ),
+    (
+        "oom_notifier_events_total",
+        "Events sent to the notifiers",
+        &EVENTS_EMITTED,
+    ),
+];
+
+pub fn inc(counter: &AtomicU64, value: u64) {
+    counter.fetch_add(value, Ordering::Relaxed);
+}
+
+pub fn get(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+fn render() -> String {
+    let mut text = String::new();
+    for (name, help, counter) in COUNTERS {
+        text.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+            name,
+            help,
+            name,
+            name,
+            get(counter)
+        ));
+    }
+    text
+}
+
+fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    // every path answers with the metrics, the request itself is not interesting
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request)?;
+
+    let body = render();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+/// Serve the metrics over HTTP on the given address, it never returns unless the address can't be bound
+pub fn serve(address: &str) -> Result<(), String> {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            return Err(format!(
+                "Could not listen for metrics requests on {}: {}",
+                address, e
+            ))
+        }
+    };
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = respond(stream) {
+                    debug!("Could not answer a metrics request: {}", e);
+                }
+            }
+            Err(e) => debug!("Could not accept a metrics request: {}", e),
+        }
+    }
+
+    Ok(())
+}
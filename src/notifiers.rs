@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::process;
 use std::time::Duration;
 
@@ -5,6 +6,145 @@ use elasticsearch::{http::transport::Transport, Elasticsearch, IndexParts};
 use kafka::producer::{Producer, Record, RequiredAcks};
 use serde_json::json;
 use syslog::{Facility, Formatter3164};
+use tokio::runtime::Runtime;
+
+use crate::incident;
+
+pub const BACKENDS: &[&str] = &["elasticsearch", "kafka", "slack", "syslog"];
+
+/// Parse a route of the form backend=scope1,scope2
+pub fn parse_route(route: &str) -> Result<(String, Vec<String>), String> {
+    let (backend, scopes) = match route.split_once('=') {
+        Some((backend, scopes)) => (backend.trim(), scopes),
+        None => {
+            return Err(format!(
+                "'{}' must have the form backend=scope1,scope2",
+                route
+            ))
+        }
+    };
+
+    if !BACKENDS.contains(&backend) {
+        return Err(format!(
+            "Unknown backend '{}', the backends are {}",
+            backend,
+            BACKENDS.join("/")
+        ));
+    }
+
+    Ok((backend.to_string(), incident::OomScope::parse_list(scopes)?))
+}
+
+#[derive(Clone, Default)]
+pub struct Notifiers {
+    pub syslog_proto: String,
+    pub syslog_server: String,
+    pub elasticsearch_server: String,
+    pub elasticsearch_index: String,
+    pub kafka_brokers: String,
+    pub kafka_topic: String,
+    pub slack_webhook: String,
+    pub slack_channel: String,
+    // backend name -> scopes of the events it receives, backends without a route receive every event
+    pub routes: HashMap<String, Vec<String>>,
+}
+
+impl Notifiers {
+    fn routed(&self, backend: &str, event: &serde_json::Value) -> bool {
+        match (self.routes.get(backend), event["scope"].as_str()) {
+            (Some(scopes), Some(scope)) => scopes.iter().any(|s| s == scope),
+            _ => true,
+        }
+    }
+
+    /// Send the event to every backend that has been configured
+    pub fn notify(&self, event: &serde_json::Value) {
+        if !self.elasticsearch_index.is_empty()
+            && !self.elasticsearch_server.is_empty()
+            && self.routed("elasticsearch", event)
+        {
+            match Runtime::new() {
+                Ok(rt) => {
+                    info!("Sending event to Elasticsearch");
+
+                    match rt.block_on(elasticsearch_notifier(
+                        event,
+                        self.elasticsearch_index.to_string(),
+                        self.elasticsearch_server.to_string(),
+                    )) {
+                        Err(e) => error!("Error while sending the oom event to the configured Elasticsearch: {}", e),
+                        _ => info!("OOM event successfully indexed in Elasticsearch"),
+                    }
+                },
+                Err(e) => error!("Could not create a tokyo runtime instance to send the event to Elasticsearch: {}", e)
+            }
+        }
+
+        if !self.slack_channel.is_empty()
+            && !self.slack_webhook.is_empty()
+            && self.routed("slack", event)
+        {
+            match Runtime::new() {
+                Ok(rt) => {
+                    info!("Sending event to Slack on channel {}", self.slack_channel);
+
+                    match rt.block_on(slack_notifier(
+                        event,
+                        self.slack_webhook.to_string(),
+                        self.slack_channel.to_string(),
+                    )) {
+                        Err(e) => error!(
+                            "Error while sending the oom event to the configured slack webhook: {}",
+                            e
+                        ),
+                        _ => info!("OOM event successfully delivered to Slack"),
+                    }
+                }
+                Err(e) => error!(
+                    "Could not create a tokyo runtime instance to send the event to Slack: {}",
+                    e
+                ),
+            }
+        }
+
+        if !self.kafka_topic.is_empty()
+            && !self.kafka_brokers.is_empty()
+            && self.routed("kafka", event)
+        {
+            info!("Sending event to Kafka");
+
+            match kafka_notifier(
+                &event.to_string(),
+                self.kafka_topic.to_string(),
+                self.kafka_brokers.split(',').map(str::to_string).collect(),
+            ) {
+                Err(e) => error!(
+                    "Error while sending the oom event to the configured Kafka: {}",
+                    e
+                ),
+                _ => info!("OOM event successfully delivered to Kafka"),
+            }
+        }
+
+        if (self.syslog_proto == "unix"
+            || (!self.syslog_proto.is_empty() && !self.syslog_server.is_empty()))
+            && self.routed("syslog", event)
+        {
+            info!("Sending event to syslog");
+            match syslog_notifier(
+                &event.to_string(),
+                self.syslog_proto.to_string(),
+                self.syslog_server.to_string(),
+            ) {
+                Err(e) => error!(
+                    "Error while sending the oom event to the configured syslog: {}",
+                    e
+                ),
+                _ => info!("OOM event successfully delivered to Syslog"),
+            }
+        }
+    }
+}
 
 pub fn syslog_notifier(message: &String, proto: String, server: String) -> Result<String, String> {
     let formatter = Formatter3164 {
@@ -46,7 +186,7 @@ pub async fn elasticsearch_notifier(
     server: String,
 ) -> Result<String, String> {
     match Transport::single_node(&server.to_string()) {
-        Err(e) => return Err(e.to_string()),
+        Err(e) => Err(e.to_string()),
         Ok(transport) => match Elasticsearch::new(transport)
             .index(IndexParts::Index(&index.to_string()))
             .body(message)
@@ -69,35 +209,68 @@ pub fn kafka_notifier(
         .with_required_acks(RequiredAcks::One) /* same here */
         .create()
     {
-        Err(e) => {
-            return Err(format!(
-                "Could not instantiate the kafka producer: {}",
-                e.to_string()
-            ))
-        }
+        Err(e) => Err(format!("Could not instantiate the kafka producer: {}", e)),
         Ok(mut producer) => match producer.send(&Record::from_value(&topic, message.as_bytes())) {
-            Err(e) => Err(format!(
-                "Error while producing the event to kafka: {}",
-                e.to_string()
-            )),
+            Err(e) => Err(format!("Error while producing the event to kafka: {}", e)),
             Ok(_) => Ok("".to_string()),
         },
     }
 }
 
+// e.g. payments/api-7f9c container api OOMKilled
+fn slack_pod_line(message: &serde_json::Value) -> Option<String> {
+    let pod = message["kubernetes"].as_object()?;
+    let mut line = format!(
+        "{}/{}",
+        pod.get("namespace")?.as_str()?,
+        pod.get("pod")?.as_str()?
+    );
+    if let Some(container) = pod.get("container").and_then(|c| c.as_str()) {
+        line.push_str(&format!(" container {}", container));
+    }
+    line.push_str(" OOMKilled\n");
+    Some(line)
+}
+
+fn slack_text(message: &serde_json::Value) -> String {
+    match message["event"].as_str() {
+        Some("oom_kill") | None => format!(
+            "{}- cmdline:{}\n- pid:{}\n- hostname:{}\n- kernel:{}",
+            slack_pod_line(message).unwrap_or_default(),
+            message["cmdline"].as_str().unwrap_or("N/A"),
+            message["pid"].as_str().unwrap_or("N/A"),
+            message["hostname"].as_str().unwrap_or("N/A"),
+            message["kernel"].as_str().unwrap_or("N/A")
+        ),
+        // the other events are rendered with all their non empty scalar fields
+        Some(event) => {
+            let mut text = format!("- event:{}", event);
+            if let Some(fields) = message.as_object() {
+                for (key, value) in fields {
+                    match value {
+                        serde_json::Value::String(v) if key != "event" => {
+                            text.push_str(&format!("\n- {}:{}", key, v))
+                        }
+                        serde_json::Value::Number(v) => {
+                            text.push_str(&format!("\n- {}:{}", key, v))
+                        }
+                        serde_json::Value::Bool(v) => text.push_str(&format!("\n- {}:{}", key, v)),
+                        _ => {}
+                    }
+                }
+            }
+            text
+        }
+    }
+}
+
 pub async fn slack_notifier(
     message: &serde_json::Value,
     webhook: String,
     channel: String,
 ) -> Result<String, String> {
     let client = reqwest::Client::new();
-    let text_to_display = format!(
-        "- cmdline:{}\n- pid:{}\n- hostname:{}\n- kernel:{}",
-        message["cmdline"].as_str().unwrap(),
-        message["pid"].as_str().unwrap(),
-        message["hostname"].as_str().unwrap(),
-        message["kernel"].as_str().unwrap()
-    );
+    let text_to_display = slack_text(message);
     let payload = json!({
         "channel": channel,
         "text": text_to_display,
@@ -114,7 +287,7 @@ pub async fn slack_notifier(
                 ));
             }
 
-            return Ok("".to_string());
+            Ok("".to_string())
         }
         Err(e) => Err(e.to_string()),
     }
@@ -0,0 +1,606 @@
+/*
+    Parsing of the kernel log lines emitted by the oom-killer.
+
+    Example of a line we want to parse:
+    Out of memory: Killed process 9865 (oom_trigger) total-vm:7468696kB, anon-rss:3942528kB, file-rss:4kB, shmem-rss:0kB, UID:1000 pgtables:7756kB oom_score_adj:0
+
+    The kill line changed a lot across kernel versions, these variants are supported too:
+    Killed process 2876, UID 500, (java) total-vm:9934040kB, anon-rss:7620516kB, file-rss:12kB    (RHEL 6 kernels)
+    Killed process 12345 (python3.6) total-vm:8264444kB, anon-rss:7589632kB, file-rss:0kB, shmem-rss:0kB    (3.x and 4.x)
+    Killed process 123 (java), UID 1000, total-vm:1000kB    (vendor kernels)
+*/
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KilledProcess {
+    pub pid: i32,
+    pub comm: String,
+    pub total_vm_kb: Option<u64>,
+    pub anon_rss_kb: Option<u64>,
+    pub file_rss_kb: Option<u64>,
+    pub shmem_rss_kb: Option<u64>,
+    pub uid: Option<u32>,
+    pub pgtables_kb: Option<u64>,
+    pub oom_score_adj: Option<i32>,
+}
+
+// the fields printed after the comm, the first one found marks the end of the comm
+const KILLED_PROCESS_FIELDS: &[&str] = &[" total-vm:", ", UID "];
+
+fn parse_kb(value: &str) -> Option<u64> {
+    value.trim_end_matches("kB").parse::<u64>().ok()
+}
+
+pub fn parse_killed_process(message: &str) -> Option<KilledProcess> {
+    let start = message.find("Killed process ")? + "Killed process ".len();
+    let rest = &message[start..];
+
+    let pid_end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let pid = rest[..pid_end].parse::<i32>().ok()?;
+    let mut rest = &rest[pid_end..];
+
+    let mut uid = None;
+    if let Some(uid_field) = rest.strip_prefix(", UID ") {
+        let uid_end = uid_field.find(',')?;
+        uid = uid_field[..uid_end].trim().parse::<u32>().ok();
+        rest = &uid_field[uid_end + 1..];
+    }
+    let rest = rest.trim_start();
+
+    // the comm is enclosed in parentheses and may itself contain spaces, digits or parentheses,
+    // so it ends at the last ')' that precedes the other fields
+    if !rest.starts_with('(') {
+        return None;
+    }
+    let fields_start = KILLED_PROCESS_FIELDS
+        .iter()
+        .filter_map(|field| rest.find(field))
+        .min()
+        .unwrap_or(rest.len());
+    let comm_end = rest[..fields_start].rfind(')')?;
+
+    let mut killed = KilledProcess {
+        pid,
+        comm: rest[1..comm_end].to_string(),
+        uid,
+        ..Default::default()
+    };
+
+    let mut fields = rest[comm_end + 1..]
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|field| !field.is_empty());
+
+    while let Some(field) = fields.next() {
+        let (key, value) = match field.split_once(':') {
+            Some(kv) => kv,
+            // some kernels separate the uid from its value with a space
+            None if field == "UID" => (field, fields.next().unwrap_or("")),
+            None => continue,
+        };
+
+        match key {
+            "total-vm" => killed.total_vm_kb = parse_kb(value),
+            "anon-rss" => killed.anon_rss_kb = parse_kb(value),
+            "file-rss" => killed.file_rss_kb = parse_kb(value),
+            "shmem-rss" => killed.shmem_rss_kb = parse_kb(value),
+            "UID" => killed.uid = value.parse::<u32>().ok(),
+            "pgtables" => killed.pgtables_kb = parse_kb(value),
+            "oom_score_adj" => killed.oom_score_adj = value.parse::<i32>().ok(),
+            _ => debug!("Ignoring unknown field {} in the oom-killer log line", key),
+        }
+    }
+
+    Some(killed)
+}
+
+/*
+    Example of the summary line printed by modern kernels right before the kill line:
+    oom-kill:constraint=CONSTRAINT_MEMCG,nodemask=(null),cpuset=/,mems_allowed=0,oom_memcg=/kubepods/pod1,task_memcg=/kubepods/pod1/c1,task=stress,pid=1234,uid=0
+*/
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OomConstraint {
+    pub constraint: String,
+    pub nodemask: Option<String>,
+    pub cpuset: Option<String>,
+    pub oom_memcg: Option<String>,
+    pub task_memcg: Option<String>,
+    pub task: Option<String>,
+    pub pid: Option<i32>,
+    pub uid: Option<u32>,
+}
+
+pub fn parse_oom_constraint(message: &str) -> Option<OomConstraint> {
+    let start = message.find("oom-kill:")? + "oom-kill:".len();
+    let mut constraint = OomConstraint::default();
+
+    // mems_allowed can be a comma separated list, its trailing items have no key and are skipped
+    for field in message[start..].trim_end().split(',') {
+        let (key, value) = match field.split_once('=') {
+            Some(kv) => kv,
+            None => continue,
+        };
+
+        match key {
+            "constraint" => constraint.constraint = value.to_string(),
+            "nodemask" => constraint.nodemask = Some(value.to_string()),
+            "cpuset" => constraint.cpuset = Some(value.to_string()),
+            "oom_memcg" => constraint.oom_memcg = Some(value.to_string()),
+            "task_memcg" => constraint.task_memcg = Some(value.to_string()),
+            "task" => constraint.task = Some(value.to_string()),
+            "pid" => constraint.pid = value.parse::<i32>().ok(),
+            "uid" => constraint.uid = value.parse::<u32>().ok(),
+            _ => debug!(
+                "Ignoring unknown field {} in the oom-kill summary line",
+                key
+            ),
+        }
+    }
+
+    if constraint.constraint.is_empty() {
+        return None;
+    }
+
+    Some(constraint)
+}
+
+/*
+    Example of the header line opening an oom-killer report, followed by the stack dump of the task:
+    stress invoked oom-killer: gfp_mask=0x100cca(GFP_HIGHUSER_MOVABLE), order=0, oom_score_adj=0
+    CPU: 1 PID: 4242 Comm: stress Not tainted 5.10.0-8-amd64 #1 Debian 5.10.46-4
+
+    Up to 4.18 the header also tells the nodemask of the allocation and the cpuset of the task:
+    stress invoked oom-killer: gfp_mask=0x14200ca(GFP_HIGHUSER_MOVABLE), nodemask=(null), order=0, oom_score_adj=0
+    stress cpuset=/ mems_allowed=0
+*/
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OomInvocation {
+    pub comm: String,
+    pub gfp_mask: Option<String>,
+    pub nodemask: Option<String>,
+    pub order: Option<i32>,
+    pub oom_score_adj: Option<i32>,
+}
+
+pub fn parse_oom_invocation(message: &str) -> Option<OomInvocation> {
+    let header_start = message.find(" invoked oom-killer:")?;
+    let mut invocation = OomInvocation {
+        comm: message[..header_start].trim().to_string(),
+        ..Default::default()
+    };
+
+    for field in message[header_start + " invoked oom-killer:".len()..].split(", ") {
+        let (key, value) = match field.trim().split_once('=') {
+            Some(kv) => kv,
+            None => continue,
+        };
+
+        match key {
+            "gfp_mask" => invocation.gfp_mask = Some(value.to_string()),
+            "nodemask" => invocation.nodemask = Some(value.to_string()),
+            "order" => invocation.order = value.parse::<i32>().ok(),
+            "oom_score_adj" => invocation.oom_score_adj = value.parse::<i32>().ok(),
+            _ => debug!(
+                "Ignoring unknown field {} in the oom-killer header line",
+                key
+            ),
+        }
+    }
+
+    Some(invocation)
+}
+
+pub fn parse_cpuset(message: &str) -> Option<String> {
+    let start = message.find(" cpuset=")? + " cpuset=".len();
+    let end = message.rfind(" mems_allowed=")?;
+    if end < start {
+        return None;
+    }
+
+    Some(message[start..end].to_string())
+}
+
+pub fn parse_stack_dump_pid(message: &str) -> Option<i32> {
+    if !message.starts_with("CPU: ") {
+        return None;
+    }
+
+    let start = message.find(" PID: ")? + " PID: ".len();
+    let rest = message[start..].trim_start();
+    rest[..rest.find(' ').unwrap_or(rest.len())]
+        .parse::<i32>()
+        .ok()
+}
+
+/*
+    Example of the first lines of the Mem-Info dump, values are expressed in pages:
+    Mem-Info:
+    active_anon:2008 inactive_anon:981349 isolated_anon:0
+     active_file:38 inactive_file:55 isolated_file:0
+*/
+
+const MEM_INFO_COUNTERS: &[&str] = &[
+    "active_anon",
+    "inactive_anon",
+    "isolated_anon",
+    "active_file",
+    "inactive_file",
+    "isolated_file",
+    "unevictable",
+    "dirty",
+    "writeback",
+    "slab_reclaimable",
+    "slab_unreclaimable",
+    "mapped",
+    "shmem",
+    "pagetables",
+    "bounce",
+    "free",
+    "free_pcp",
+    "free_cma",
+];
+
+pub fn parse_mem_info_counters(message: &str) -> Vec<(String, u64)> {
+    message
+        .split_whitespace()
+        .filter_map(|field| field.split_once(':'))
+        .filter(|(key, _)| MEM_INFO_COUNTERS.contains(key))
+        .filter_map(|(key, value)| value.parse::<u64>().ok().map(|v| (key.to_string(), v)))
+        .collect()
+}
+
+/*
+    Example of the table of eligible tasks printed when vm.oom_dump_tasks is enabled, memory values are in pages:
+    [  pid  ]   uid  tgid total_vm      rss pgtables_bytes swapents oom_score_adj name
+    [    512]     0   512    11638      715   126976        0             0 systemd-journal
+    Older kernels print nr_ptes (and nr_pmds) instead of pgtables_bytes, the header is used to map the columns.
+*/
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TaskEntry {
+    pub pid: i32,
+    pub uid: Option<u32>,
+    pub tgid: Option<i32>,
+    pub total_vm_pages: Option<u64>,
+    pub rss_pages: Option<u64>,
+    pub pgtables_bytes: Option<u64>,
+    pub swap_entries: Option<u64>,
+    pub oom_score_adj: Option<i32>,
+    pub name: String,
+}
+
+fn split_task_table_pid(message: &str) -> Option<(&str, &str)> {
+    let line = message.trim_start().strip_prefix('[')?;
+    let end = line.find(']')?;
+    Some((line[..end].trim(), &line[end + 1..]))
+}
+
+pub fn parse_task_table_header(message: &str) -> Option<Vec<String>> {
+    match split_task_table_pid(message)? {
+        ("pid", columns) => Some(columns.split_whitespace().map(str::to_string).collect()),
+        _ => None,
+    }
+}
+
+pub fn parse_task_table_row(message: &str, columns: &[String]) -> Option<TaskEntry> {
+    let (pid, rest) = split_task_table_pid(message)?;
+    let mut task = TaskEntry {
+        pid: pid.parse::<i32>().ok()?,
+        ..Default::default()
+    };
+
+    // every column is numeric except the name, the last one, which can contain spaces
+    let (name_column, numeric_columns) = columns.split_last()?;
+    let mut fields = rest.split_whitespace();
+    for column in numeric_columns {
+        let value = fields.next()?;
+        match column.as_str() {
+            "uid" => task.uid = value.parse::<u32>().ok(),
+            "tgid" => task.tgid = value.parse::<i32>().ok(),
+            "total_vm" => task.total_vm_pages = value.parse::<u64>().ok(),
+            "rss" => task.rss_pages = value.parse::<u64>().ok(),
+            "pgtables_bytes" => task.pgtables_bytes = value.parse::<u64>().ok(),
+            "swapents" => task.swap_entries = value.parse::<u64>().ok(),
+            "oom_score_adj" => task.oom_score_adj = value.parse::<i32>().ok(),
+            _ => {}
+        }
+    }
+
+    if name_column != "name" {
+        debug!(
+            "Unexpected last column {} in the oom-killer task table",
+            name_column
+        );
+    }
+    task.name = fields.collect::<Vec<&str>>().join(" ");
+
+    Some(task)
+}
+
+/*
+    Example of the line printed once the oom_reaper has released the memory of the killed process:
+    oom_reaper: reaped process 9865 (oom_trigger), now anon-rss:0kB, file-rss:0kB, shmem-rss:0kB
+*/
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReapedProcess {
+    pub pid: i32,
+    pub comm: String,
+    pub anon_rss_kb: Option<u64>,
+    pub file_rss_kb: Option<u64>,
+    pub shmem_rss_kb: Option<u64>,
+}
+
+pub fn parse_reaped_process(message: &str) -> Option<ReapedProcess> {
+    let start = message.find("oom_reaper: reaped process ")? + "oom_reaper: reaped process ".len();
+    let rest = &message[start..];
+
+    let pid_end = rest.find(' ').unwrap_or(rest.len());
+    let pid = rest[..pid_end].parse::<i32>().ok()?;
+    let rest = rest[pid_end..].trim_start().strip_prefix('(')?;
+
+    let comm_end = rest.rfind("), now").or_else(|| rest.rfind(')'))?;
+    let mut reaped = ReapedProcess {
+        pid,
+        comm: rest[..comm_end].to_string(),
+        ..Default::default()
+    };
+
+    for field in rest[comm_end + 1..].split(|c: char| c == ',' || c.is_whitespace()) {
+        match field.split_once(':') {
+            Some(("anon-rss", value)) => reaped.anon_rss_kb = parse_kb(value),
+            Some(("file-rss", value)) => reaped.file_rss_kb = parse_kb(value),
+            Some(("shmem-rss", value)) => reaped.shmem_rss_kb = parse_kb(value),
+            _ => {}
+        }
+    }
+
+    Some(reaped)
+}
+
+/*
+    Example of the lines printed when the oom-killer could not free memory by killing a process:
+    Out of memory and no killable processes...
+    Kernel panic - not syncing: System is deadlocked on memory
+    Kernel panic - not syncing: Out of memory: system-wide panic_on_oom is enabled
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OomFailure {
+    NoKillableProcesses,
+    Panic,
+}
+
+pub fn parse_oom_failure(message: &str) -> Option<OomFailure> {
+    if message.contains("Out of memory and no killable processes") {
+        Some(OomFailure::NoKillableProcesses)
+    } else if message.contains("Kernel panic - not syncing: Out of memory")
+        || message.contains("Kernel panic - not syncing: System is deadlocked on memory")
+    {
+        Some(OomFailure::Panic)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn killed_process_line() {
+        let killed = parse_killed_process(
+            "Out of memory: Killed process 9865 (oom_trigger) total-vm:7468696kB, anon-rss:3942528kB, file-rss:4kB, shmem-rss:0kB, UID:1000 pgtables:7756kB oom_score_adj:0",
+        );
+        assert_eq!(
+            killed,
+            Some(KilledProcess {
+                pid: 9865,
+                comm: "oom_trigger".to_string(),
+                total_vm_kb: Some(7468696),
+                anon_rss_kb: Some(3942528),
+                file_rss_kb: Some(4),
+                shmem_rss_kb: Some(0),
+                uid: Some(1000),
+                pgtables_kb: Some(7756),
+                oom_score_adj: Some(0),
+            })
+        );
+    }
+
+    #[test]
+    fn killed_process_comm_with_spaces() {
+        let killed = parse_killed_process(
+            "Out of memory: Killed process 9981 (tmux: server) total-vm:540532kB, anon-rss:523640kB",
+        )
+        .unwrap();
+        assert_eq!(killed.pid, 9981);
+        assert_eq!(killed.comm, "tmux: server");
+        assert_eq!(killed.anon_rss_kb, Some(523640));
+        assert_eq!(killed.oom_score_adj, None);
+    }
+
+    #[test]
+    fn not_a_killed_process() {
+        assert_eq!(
+            parse_killed_process(
+                "Out of memory: Kill process 9865 (oom_trigger) score 1986 or sacrifice child"
+            ),
+            None
+        );
+        assert_eq!(
+            parse_killed_process("Killed process abc (oom_trigger) total-vm:10kB"),
+            None
+        );
+        assert_eq!(
+            parse_killed_process("usb 1-1: new high-speed USB device number 2"),
+            None
+        );
+    }
+
+    // the corpus of kernel log excerpts, with the kill line each one is expected to contain
+    fn fixtures() -> Vec<(&'static str, &'static str, KilledProcess)> {
+        vec![
+            (
+                "2.6.32-rhel6",
+                include_str!("../tests/fixtures/oom/2.6.32-rhel6.log"),
+                KilledProcess {
+                    pid: 2876,
+                    comm: "java".to_string(),
+                    total_vm_kb: Some(9934040),
+                    anon_rss_kb: Some(7620516),
+                    file_rss_kb: Some(12),
+                    uid: Some(500),
+                    ..Default::default()
+                },
+            ),
+            (
+                "3.10-centos7",
+                include_str!("../tests/fixtures/oom/3.10-centos7.log"),
+                KilledProcess {
+                    pid: 12345,
+                    comm: "python3.6".to_string(),
+                    total_vm_kb: Some(8264444),
+                    anon_rss_kb: Some(7589632),
+                    file_rss_kb: Some(0),
+                    shmem_rss_kb: Some(0),
+                    ..Default::default()
+                },
+            ),
+            (
+                "4.9-debian9",
+                include_str!("../tests/fixtures/oom/4.9-debian9.log"),
+                KilledProcess {
+                    pid: 2201,
+                    comm: "Web Content".to_string(),
+                    total_vm_kb: Some(3262436),
+                    anon_rss_kb: Some(1402440),
+                    file_rss_kb: Some(0),
+                    shmem_rss_kb: Some(4180),
+                    ..Default::default()
+                },
+            ),
+            (
+                "4.15-ubuntu1804-memcg",
+                include_str!("../tests/fixtures/oom/4.15-ubuntu1804-memcg.log"),
+                KilledProcess {
+                    pid: 30114,
+                    comm: "node".to_string(),
+                    total_vm_kb: Some(1461992),
+                    anon_rss_kb: Some(256528),
+                    file_rss_kb: Some(29396),
+                    shmem_rss_kb: Some(0),
+                    ..Default::default()
+                },
+            ),
+            (
+                "5.10-debian11",
+                include_str!("../tests/fixtures/oom/5.10-debian11.log"),
+                KilledProcess {
+                    pid: 4242,
+                    comm: "stress".to_string(),
+                    total_vm_kb: Some(4198476),
+                    anon_rss_kb: Some(3924640),
+                    file_rss_kb: Some(8),
+                    shmem_rss_kb: Some(0),
+                    uid: Some(0),
+                    pgtables_kb: Some(7740),
+                    oom_score_adj: Some(0),
+                },
+            ),
+            (
+                "6.1-memcg",
+                include_str!("../tests/fixtures/oom/6.1-memcg.log"),
+                KilledProcess {
+                    pid: 9981,
+                    comm: "tmux: server".to_string(),
+                    total_vm_kb: Some(540532),
+                    anon_rss_kb: Some(523640),
+                    file_rss_kb: Some(0),
+                    shmem_rss_kb: Some(0),
+                    uid: Some(1000),
+                    pgtables_kb: Some(1084),
+                    oom_score_adj: Some(200),
+                },
+            ),
+        ]
+    }
+
+    fn messages(excerpt: &str) -> impl Iterator<Item = &str> {
+        excerpt
+            .lines()
+            .map(|line| line.split_once("] ").map_or(line, |(_, message)| message))
+    }
+
+    #[test]
+    fn killed_process_corpus() {
+        for (name, excerpt, expected) in fixtures() {
+            let killed: Vec<KilledProcess> =
+                messages(excerpt).filter_map(parse_killed_process).collect();
+            assert_eq!(killed, vec![expected], "fixture {}", name);
+        }
+    }
+
+    #[test]
+    fn reaped_process_corpus() {
+        for (name, excerpt, expected) in fixtures() {
+            for reaped in messages(excerpt).filter_map(parse_reaped_process) {
+                assert_eq!(reaped.pid, expected.pid, "fixture {}", name);
+                assert_eq!(reaped.comm, expected.comm, "fixture {}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn killed_process_uid_after_comm() {
+        let killed = parse_killed_process("Killed process 123 (java), UID 1000, total-vm:1000kB");
+        assert_eq!(
+            killed,
+            Some(KilledProcess {
+                pid: 123,
+                comm: "java".to_string(),
+                total_vm_kb: Some(1000),
+                uid: Some(1000),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn killed_process_comm_with_parentheses() {
+        let killed = parse_killed_process(
+            "Out of memory: Killed process 77 (a (b) 2) total-vm:10kB, anon-rss:5kB",
+        )
+        .unwrap();
+        assert_eq!(killed.pid, 77);
+        assert_eq!(killed.comm, "a (b) 2");
+        assert_eq!(killed.anon_rss_kb, Some(5));
+    }
+
+    #[test]
+    fn header_of_older_kernels() {
+        let invocation = parse_oom_invocation(
+            "stress invoked oom-killer: gfp_mask=0x14200ca(GFP_HIGHUSER_MOVABLE), nodemask=(null), order=0, oom_score_adj=0",
+        )
+        .unwrap();
+        assert_eq!(invocation.comm, "stress");
+        assert_eq!(invocation.nodemask.as_deref(), Some("(null)"));
+        assert_eq!(invocation.order, Some(0));
+
+        assert_eq!(
+            parse_cpuset("stress cpuset=/ mems_allowed=0"),
+            Some("/".to_string())
+        );
+        assert_eq!(
+            parse_cpuset("node cpuset=7d4f2e0c0a1b mems_allowed=0-1"),
+            Some("7d4f2e0c0a1b".to_string())
+        );
+        // the summary line of newer kernels is not a header line
+        assert_eq!(
+            parse_cpuset("oom-kill:constraint=CONSTRAINT_NONE,nodemask=(null),cpuset=/,mems_allowed=0,global_oom,task_memcg=/,task=stress,pid=4242,uid=0"),
+            None
+        );
+    }
+}
@@ -0,0 +1,471 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use lru::LruCache;
+
+use crate::events;
+use crate::incident;
+use crate::kubernetes;
+use crate::metrics;
+use crate::notifiers;
+use crate::parser;
+use crate::process;
+use crate::rules;
+
+/*
+    The pipeline turns the kernel log lines into events: it assembles the oom reports, matches the rules,
+    enriches the events with the command lines of the processes and sends them to the notifiers.
+    It is shared by the live kernel log reader, the backfill of the records logged before the daemon started
+    and the replay of kernel log dumps.
+*/
+
+const PANIC_ANNOUNCEMENT: &str =
+    "The oom-killer was invoked with vm.panic_on_oom set, the host is about to panic";
+const POSSIBLE_PANIC_ANNOUNCEMENT: &str =
+    "The oom-killer was invoked with vm.panic_on_oom set, the host panics unless the oom is constrained";
+
+// what the header of a report tells about the panic to come
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PanicOutlook {
+    Unlikely,
+    // the constraint of the oom is only printed with the kill, after the panic would have happened
+    Possible,
+    Certain,
+}
+
+fn panic_outlook(panic_on_oom: Option<u32>, incident: &incident::Incident) -> PanicOutlook {
+    match panic_on_oom {
+        Some(2) => PanicOutlook::Certain,
+        // 1 panics on the unconstrained ooms only, the memory cgroup ones are told apart by their header
+        Some(1) if incident.scope() != incident::OomScope::Global => PanicOutlook::Unlikely,
+        Some(1) if incident.unconstrained_header() => PanicOutlook::Certain,
+        Some(1) => PanicOutlook::Possible,
+        _ => PanicOutlook::Unlikely,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Live,
+    // the processes of replayed lines are long gone, so their events are sent even without a command line
+    Replay,
+    // the pids of backfilled lines may have been reused since, so the process cache is not used at all
+    Backfill,
+}
+
+// where every detector sends its events, the watchers running in their own thread get a clone of it
+#[derive(Clone)]
+pub struct Sink {
+    pub notifiers: notifiers::Notifiers,
+    pub oom_scopes: Vec<String>,
+    // the events naming a container are enriched with the metadata of its pod
+    pub kubernetes: Option<kubernetes::PodDirectory>,
+}
+
+impl Sink {
+    pub fn in_scope(&self, scope: &str) -> bool {
+        self.oom_scopes.iter().any(|s| s == scope)
+    }
+
+    pub fn emit(&self, event: &serde_json::Value) {
+        metrics::inc(&metrics::EVENTS_EMITTED, 1);
+
+        match self.kubernetes.as_ref().and_then(|k| k.enrich(event)) {
+            Some(enriched) => {
+                debug!("Event enriched with the pod metadata: {}", &enriched);
+                self.notifiers.notify(&enriched)
+            }
+            None => self.notifiers.notify(event),
+        }
+    }
+}
+
+pub struct Pipeline {
+    pub processes: Arc<Mutex<LruCache<i32, process::ProcessInfo>>>,
+    pub oom_candidates: usize,
+    pub kernel_rules: Vec<rules::Rule>,
+    pub sink: Sink,
+    pub mode: Mode,
+    pub assembler: incident::IncidentAssembler,
+    // the panic of the current report was reported before its panic line
+    pub panic_announced: bool,
+}
+
+impl Pipeline {
+    pub fn process_line(&mut self, line: &str, timestamp: time::Duration) {
+        match self.assembler.process(line, timestamp) {
+            // a report is over, the next panic line is reported unless its own header announced it
+            Some(incident::Report::Kill(incident)) => {
+                self.panic_announced = false;
+                self.count_kill(&metrics::OOM_KILLS_DETECTED);
+                self.report_incident(&incident)
+            }
+            Some(incident::Report::Reaped(reaping)) => self.report_reaping(&reaping),
+            Some(incident::Report::Dumping(incident)) => self.announce_panic(&incident),
+            Some(incident::Report::Failure(_, parser::OomFailure::Panic))
+                if self.panic_announced =>
+            {
+                self.panic_announced = false
+            }
+            Some(incident::Report::Failure(incident, failure)) => {
+                self.panic_announced = false;
+                self.report_failure(&incident, failure, line)
+            }
+            None => {}
+        }
+
+        if let Some(rule_match) = rules::first_match(&self.kernel_rules, line) {
+            self.report_rule_match(&rule_match, line);
+        }
+    }
+
+    fn cached_cmdline(
+        &self,
+        procs: &LruCache<i32, process::ProcessInfo>,
+        pid: Option<i32>,
+    ) -> Option<String> {
+        match (self.mode, pid) {
+            (Mode::Backfill, _) | (_, None) => None,
+            (_, Some(pid)) => procs.peek(&pid).map(|p| p.cmdline.clone()),
+        }
+    }
+
+    // the kills of replayed lines were counted by the kernel of another boot, or of another host
+    fn count_kill(&self, counter: &AtomicU64) {
+        if self.mode != Mode::Replay {
+            metrics::inc(counter, 1);
+        }
+    }
+
+    fn emit(&self, mut event: serde_json::Value) {
+        match self.mode {
+            Mode::Live => {}
+            Mode::Replay => event["replayed"] = serde_json::Value::Bool(true),
+            Mode::Backfill => event["backfilled"] = serde_json::Value::Bool(true),
+        }
+
+        self.sink.emit(&event);
+    }
+
+    /// Report the records lost between two reads of the kernel log
+    pub fn report_dropped(&self, first_sequence: u64, last_sequence: u64) {
+        metrics::inc(
+            &metrics::KERNEL_LOG_RECORDS_DROPPED,
+            last_sequence + 1 - first_sequence,
+        );
+
+        let dropped_event = events::build_dropped_event(first_sequence, last_sequence);
+        warn!("Kernel log records dropped: {}", &dropped_event);
+        self.emit(dropped_event);
+    }
+
+    /// Report the kills counted by the kernel that were not found in the kernel log
+    pub fn report_missed_kills(&self, expected: u64, handled: u64, missed: u64) {
+        let newly_missed = missed - metrics::get(&metrics::OOM_KILLS_MISSED);
+        metrics::inc(&metrics::OOM_KILLS_MISSED, newly_missed);
+
+        let missed_event = events::build_missed_kills_event(
+            expected,
+            metrics::get(&metrics::OOM_KILLS_DETECTED),
+            handled,
+            missed,
+        );
+        warn!("Missed OOM kills: {}", &missed_event);
+        self.emit(missed_event);
+    }
+
+    fn trigger_cmdline(&self, incident: &incident::Incident) -> Option<String> {
+        match self.processes.lock() {
+            Ok(procs) => self.cached_cmdline(&procs, incident.trigger.as_ref().and_then(|t| t.pid)),
+            Err(e) => {
+                error!("Could not acquire the process table lock in the kernel-log-refresher thread!. Error: {}", e);
+                None
+            }
+        }
+    }
+
+    fn report_failure(
+        &self,
+        incident: &incident::Incident,
+        failure: parser::OomFailure,
+        message: &str,
+    ) {
+        // nothing was killed, these are always reported whatever scopes have been selected
+        let failure_event =
+            events::build_failure_event(incident, failure, message, self.trigger_cmdline(incident));
+        error!(
+            "OOM could not be resolved by killing a process: {}",
+            &failure_event
+        );
+        self.emit(failure_event);
+    }
+
+    /// With vm.panic_on_oom the host stops right after printing the report, its panic line is never read:
+    /// the panic is reported as soon as the header of the report is read (1: unconstrained ooms only, 2: all of them)
+    fn announce_panic(&mut self, incident: &incident::Incident) {
+        self.panic_announced = false;
+        // the replayed and backfilled reports did not make this host panic
+        if self.mode != Mode::Live {
+            return;
+        }
+
+        match panic_outlook(events::get_panic_on_oom(), incident) {
+            PanicOutlook::Certain => {
+                self.panic_announced = true;
+                self.report_failure(incident, parser::OomFailure::Panic, PANIC_ANNOUNCEMENT);
+            }
+            // the panic line is still reported if it can be read
+            PanicOutlook::Possible => {
+                let panic_event = events::build_possible_panic_event(
+                    incident,
+                    POSSIBLE_PANIC_ANNOUNCEMENT,
+                    self.trigger_cmdline(incident),
+                );
+                warn!("OOM that may panic the host: {}", &panic_event);
+                self.emit(panic_event);
+            }
+            PanicOutlook::Unlikely => {}
+        }
+    }
+
+    fn report_rule_match(&self, rule_match: &rules::RuleMatch, message: &str) {
+        // the process may still be alive (e.g. a hung task) so it is left in the cache
+        let cmdline = match self.processes.lock() {
+            Ok(procs) => self.cached_cmdline(&procs, rule_match.pid),
+            Err(e) => {
+                error!("Could not acquire the process table lock in the kernel-log-refresher thread!. Error: {}", e);
+                None
+            }
+        };
+
+        if rule_match.pid.is_some() && cmdline.is_none() && self.mode == Mode::Live {
+            warn!(
+                "Kernel log line matched the rule {} but could not obtain informations about the process {:?}",
+                rule_match.rule, rule_match.pid
+            );
+        }
+
+        let rule_event = events::build_rule_event(rule_match, message, cmdline);
+        info!("New kernel log event: {}", &rule_event);
+        self.emit(rule_event);
+    }
+
+    fn report_reaping(&self, reaping: &incident::Reaping) {
+        // follow-ups of kills that were filtered out are not interesting either
+        if let Some(scope) = reaping.scope {
+            if !self.sink.in_scope(scope.as_str()) {
+                return;
+            }
+        }
+
+        let reaped_event = events::build_reaped_event(reaping);
+        info!("OOM victim reaped: {}", &reaped_event);
+        self.emit(reaped_event);
+    }
+
+    fn report_incident(&self, incident: &incident::Incident) {
+        let pid = incident.victim.pid;
+        let scope = incident.scope().as_str();
+
+        if !self.sink.in_scope(scope) {
+            info!(
+                "Ignoring OOM for pid {} since its scope {} is filtered out",
+                pid, scope
+            );
+            self.count_kill(&metrics::OOM_KILLS_FILTERED);
+            return;
+        }
+
+        let oom_event = match self.processes.lock() {
+            Ok(mut procs) => {
+                let trigger_cmdline =
+                    self.cached_cmdline(&procs, incident.trigger.as_ref().and_then(|t| t.pid));
+
+                let process = match self.mode {
+                    Mode::Backfill => None,
+                    _ => procs.pop(&pid),
+                };
+                // the kill line alone still tells the comm, the memory and the cgroups of the victim
+                if process.is_none() && self.mode == Mode::Live {
+                    warn!(
+                        "Detected OOM for pid {} but could not obtain informations about the process",
+                        pid
+                    );
+                }
+
+                let candidates =
+                    events::build_oom_candidates(incident, self.oom_candidates, |pid| {
+                        self.cached_cmdline(&procs, Some(pid))
+                    });
+                let ancestors = match &process {
+                    Some(process) => events::build_ancestors(process::ancestry(&procs, process)),
+                    None => Vec::new(),
+                };
+                events::build_oom_event(incident, process, ancestors, trigger_cmdline, candidates)
+            }
+            Err(e) => {
+                error!("Could not acquire the process table lock in the kernel-log-refresher thread!. Error: {}", e);
+                return;
+            }
+        };
+
+        info!("New OOM event: {}", &oom_event);
+        self.emit(oom_event);
+        self.count_kill(&metrics::OOM_KILLS_REPORTED);
+    }
+
+    /// The kills either sent to the notifiers or deliberately filtered out, to be compared with the kernel counter
+    pub fn handled_kills() -> u64 {
+        metrics::get(&metrics::OOM_KILLS_REPORTED) + metrics::get(&metrics::OOM_KILLS_FILTERED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    const KILL: &str =
+        "Out of memory: Killed process 4242 (stress) total-vm:4198476kB, anon-rss:3924640kB";
+    const NO_KILLABLE_PROCESSES: &str = "Out of memory and no killable processes...";
+    const PANIC: &str =
+        "Kernel panic - not syncing: Out of memory: system-wide panic_on_oom is enabled";
+
+    // a pipeline sending its events to a local syslog over TCP
+    fn pipeline(listener: &TcpListener) -> Pipeline {
+        Pipeline {
+            processes: Arc::new(Mutex::new(LruCache::new(16))),
+            oom_candidates: 0,
+            kernel_rules: Vec::new(),
+            sink: Sink {
+                notifiers: notifiers::Notifiers {
+                    syslog_proto: "tcp".to_string(),
+                    syslog_server: listener.local_addr().unwrap().to_string(),
+                    ..Default::default()
+                },
+                oom_scopes: incident::OomScope::ALL
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect(),
+                kubernetes: None,
+            },
+            mode: Mode::Live,
+            assembler: incident::IncidentAssembler::default(),
+            panic_announced: false,
+        }
+    }
+
+    // the events sent since the last call, every event is sent over its own connection
+    fn sent_events(listener: &TcpListener) -> Vec<String> {
+        listener.set_nonblocking(true).unwrap();
+        let mut events = Vec::new();
+        while let Ok((mut stream, _)) = listener.accept() {
+            stream.set_nonblocking(false).unwrap();
+            let mut message = String::new();
+            stream.read_to_string(&mut message).unwrap();
+            let event: serde_json::Value =
+                serde_json::from_str(message[message.find('{').unwrap()..].trim_end()).unwrap();
+            events.push(event["event"].as_str().unwrap().to_string());
+        }
+        events
+    }
+
+    #[test]
+    fn announced_panic_reported_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pipeline = pipeline(&listener);
+
+        // the panic line of the report whose header announced it
+        pipeline.panic_announced = true;
+        pipeline.process_line(PANIC, time::Duration::from_secs(1));
+        assert!(sent_events(&listener).is_empty());
+
+        // a later panic without a header of its own, e.g. rate limited
+        pipeline.process_line(PANIC, time::Duration::from_secs(2));
+        assert_eq!(sent_events(&listener), vec!["oom_panic"]);
+    }
+
+    #[test]
+    fn panic_after_kill() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pipeline = pipeline(&listener);
+
+        pipeline.panic_announced = true;
+        pipeline.process_line(KILL, time::Duration::from_secs(1));
+        pipeline.process_line(PANIC, time::Duration::from_secs(2));
+        assert_eq!(sent_events(&listener), vec!["oom_kill", "oom_panic"]);
+    }
+
+    #[test]
+    fn panic_after_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pipeline = pipeline(&listener);
+
+        pipeline.panic_announced = true;
+        pipeline.process_line(NO_KILLABLE_PROCESSES, time::Duration::from_secs(1));
+        pipeline.process_line(PANIC, time::Duration::from_secs(2));
+        assert_eq!(
+            sent_events(&listener),
+            vec!["oom_no_killable_processes", "oom_panic"]
+        );
+    }
+
+    // the incident announced once the header of a report is read
+    fn dumped(lines: &[&str]) -> incident::Incident {
+        let mut assembler = incident::IncidentAssembler::default();
+        lines
+            .iter()
+            .find_map(
+                |line| match assembler.process(line, time::Duration::from_secs(0)) {
+                    Some(incident::Report::Dumping(incident)) => Some(incident),
+                    _ => None,
+                },
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn panic_outlooks() {
+        let unconstrained = dumped(&[
+            "stress invoked oom-killer: gfp_mask=0x14200ca(GFP_HIGHUSER_MOVABLE), nodemask=(null), order=0, oom_score_adj=0",
+            "stress cpuset=/ mems_allowed=0",
+            "CPU: 1 PID: 4242 Comm: stress Not tainted 4.15.0-112-generic #113-Ubuntu",
+            "Mem-Info:",
+        ]);
+        let cpuset = dumped(&[
+            "java invoked oom-killer: gfp_mask=0x14200ca(GFP_HIGHUSER_MOVABLE), nodemask=(null), order=0, oom_score_adj=0",
+            "java cpuset=batch mems_allowed=1",
+            "Mem-Info:",
+        ]);
+        let memcg = dumped(&[
+            "node invoked oom-killer: gfp_mask=0x14000c0(GFP_KERNEL), nodemask=(null), order=0, oom_score_adj=999",
+            "node cpuset=/ mems_allowed=0",
+            "Task in /kubepods/pod5f1c0a3e/7d4f2e0c0a1b killed as a result of limit of /kubepods/pod5f1c0a3e",
+            "[ pid ]   uid  tgid total_vm      rss pgtables_bytes swapents oom_score_adj name",
+        ]);
+        // newer kernels only print the nodemask and the cpuset in the summary line
+        let undetermined = dumped(&[
+            "stress invoked oom-killer: gfp_mask=0x100cca(GFP_HIGHUSER_MOVABLE), order=0, oom_score_adj=0",
+            "CPU: 1 PID: 4242 Comm: stress Not tainted 5.10.0-8-amd64 #1 Debian 5.10.46-4",
+            "Mem-Info:",
+        ]);
+
+        let outlooks = vec![
+            (None, &unconstrained, PanicOutlook::Unlikely),
+            (Some(0), &unconstrained, PanicOutlook::Unlikely),
+            (Some(1), &unconstrained, PanicOutlook::Certain),
+            (Some(1), &cpuset, PanicOutlook::Possible),
+            (Some(1), &memcg, PanicOutlook::Unlikely),
+            (Some(1), &undetermined, PanicOutlook::Possible),
+            (Some(2), &cpuset, PanicOutlook::Certain),
+            (Some(2), &memcg, PanicOutlook::Certain),
+            (Some(2), &undetermined, PanicOutlook::Certain),
+        ];
+
+        for (i, (panic_on_oom, incident, outlook)) in outlooks.into_iter().enumerate() {
+            assert_eq!(panic_outlook(panic_on_oom, incident), outlook, "case {}", i);
+        }
+    }
+}
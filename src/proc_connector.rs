@@ -0,0 +1,345 @@
+use std::io;
+use std::mem;
+use std::time;
+
+/*
+    The proc connector multicasts a netlink message for every fork, exec and exit (needs CAP_NET_ADMIN):
+    nlmsghdr | cn_msg (idx=CN_IDX_PROC, val=CN_VAL_PROC) | proc_event (what, cpu, timestamp_ns, event_data)
+    e.g. what=PROC_EVENT_EXEC, event_data = process_pid 9865, process_tgid 9865
+*/
+
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+const PROC_CN_MCAST_IGNORE: u32 = 2;
+
+const PROC_EVENT_FORK: u32 = 0x0000_0001;
+const PROC_EVENT_EXEC: u32 = 0x0000_0002;
+const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+
+const NLMSG_HEADER_SIZE: usize = 16;
+const CN_MSG_SIZE: usize = 20;
+// what, cpu and timestamp_ns precede the data of the event
+const PROC_EVENT_HEADER_SIZE: usize = 16;
+const EVENTS_OFFSET: usize = NLMSG_HEADER_SIZE + CN_MSG_SIZE;
+
+// a proc_event is never bigger than this, the union of the event data included
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProcEvent {
+    Fork {
+        parent_tgid: i32,
+        child_pid: i32,
+        child_tgid: i32,
+    },
+    Exec {
+        pid: i32,
+        tgid: i32,
+    },
+    Exit {
+        pid: i32,
+        tgid: i32,
+    },
+    // the events not interesting for the cache (uid/gid changes, comm changes, ...)
+    Other,
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buffer.get(offset..offset + 4)?;
+    Some(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_i32(buffer: &[u8], offset: usize) -> Option<i32> {
+    read_u32(buffer, offset).map(|value| value as i32)
+}
+
+pub fn parse_message(buffer: &[u8]) -> Option<ProcEvent> {
+    let idx = read_u32(buffer, NLMSG_HEADER_SIZE)?;
+    let val = read_u32(buffer, NLMSG_HEADER_SIZE + 4)?;
+    if idx != CN_IDX_PROC || val != CN_VAL_PROC {
+        return None;
+    }
+
+    let what = read_u32(buffer, EVENTS_OFFSET)?;
+    let data = EVENTS_OFFSET + PROC_EVENT_HEADER_SIZE;
+
+    match what {
+        PROC_EVENT_FORK => Some(ProcEvent::Fork {
+            parent_tgid: read_i32(buffer, data + 4)?,
+            child_pid: read_i32(buffer, data + 8)?,
+            child_tgid: read_i32(buffer, data + 12)?,
+        }),
+        PROC_EVENT_EXEC => Some(ProcEvent::Exec {
+            pid: read_i32(buffer, data)?,
+            tgid: read_i32(buffer, data + 4)?,
+        }),
+        PROC_EVENT_EXIT => Some(ProcEvent::Exit {
+            pid: read_i32(buffer, data)?,
+            tgid: read_i32(buffer, data + 4)?,
+        }),
+        _ => Some(ProcEvent::Other),
+    }
+}
+
+pub struct ProcConnector {
+    fd: libc::c_int,
+    buffer: Vec<u8>,
+}
+
+impl ProcConnector {
+    /// Subscribe to the process events of the whole host
+    pub fn open() -> io::Result<ProcConnector> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_CONNECTOR,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let connector = ProcConnector {
+            fd,
+            buffer: vec![0; MAX_MESSAGE_SIZE],
+        };
+
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = CN_IDX_PROC;
+        // the kernel assigns a port id when 0 is given
+        address.nl_pid = 0;
+
+        let bound = unsafe {
+            libc::bind(
+                fd,
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        connector.send_operation(PROC_CN_MCAST_LISTEN)?;
+        Ok(connector)
+    }
+
+    fn send_operation(&self, operation: u32) -> io::Result<()> {
+        let length = NLMSG_HEADER_SIZE + CN_MSG_SIZE + 4;
+        let mut message = Vec::with_capacity(length);
+
+        // nlmsghdr: len, type, flags, seq, pid
+        message.extend_from_slice(&(length as u32).to_ne_bytes());
+        message.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+        message.extend_from_slice(&0u16.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&std::process::id().to_ne_bytes());
+        // cn_msg: idx, val, seq, ack, len, flags
+        message.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+        message.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&4u16.to_ne_bytes());
+        message.extend_from_slice(&0u16.to_ne_bytes());
+        message.extend_from_slice(&operation.to_ne_bytes());
+
+        let sent = unsafe {
+            libc::send(
+                self.fd,
+                message.as_ptr() as *const libc::c_void,
+                message.len(),
+                0,
+            )
+        };
+        match sent {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait up to timeout for the next process event, Ok(None) is returned if nothing happened in the meantime.
+    /// ENOBUFS is returned when the socket overflowed and some events were lost.
+    pub fn read(&mut self, timeout: time::Duration) -> io::Result<Option<ProcEvent>> {
+        loop {
+            if !self.wait(timeout)? {
+                return Ok(None);
+            }
+
+            let size = unsafe {
+                libc::recv(
+                    self.fd,
+                    self.buffer.as_mut_ptr() as *mut libc::c_void,
+                    self.buffer.len(),
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            if size < 0 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => continue,
+                    _ => return Err(e),
+                }
+            }
+
+            match parse_message(&self.buffer[..size as usize]) {
+                Some(event) => return Ok(Some(event)),
+                None => continue,
+            }
+        }
+    }
+
+    fn wait(&self, timeout: time::Duration) -> io::Result<bool> {
+        let mut fds = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        match unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) } {
+            -1 => {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    return Ok(false);
+                }
+                Err(e)
+            }
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+}
+
+impl Drop for ProcConnector {
+    fn drop(&mut self) {
+        let _ = self.send_operation(PROC_CN_MCAST_IGNORE);
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a message as multicast by the kernel, the event data given as its 32 bits fields
+    fn message(idx: u32, val: u32, what: u32, data: &[u32]) -> Vec<u8> {
+        let length = EVENTS_OFFSET + PROC_EVENT_HEADER_SIZE + 4 * data.len();
+        let mut message = Vec::with_capacity(length);
+
+        // nlmsghdr: len, type, flags, seq, pid
+        message.extend_from_slice(&(length as u32).to_ne_bytes());
+        message.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+        message.extend_from_slice(&0u16.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        // cn_msg: idx, val, seq, ack, len, flags
+        message.extend_from_slice(&idx.to_ne_bytes());
+        message.extend_from_slice(&val.to_ne_bytes());
+        message.extend_from_slice(&7u32.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message
+            .extend_from_slice(&((PROC_EVENT_HEADER_SIZE + 4 * data.len()) as u16).to_ne_bytes());
+        message.extend_from_slice(&0u16.to_ne_bytes());
+        // proc_event: what, cpu, timestamp_ns, event_data
+        message.extend_from_slice(&what.to_ne_bytes());
+        message.extend_from_slice(&3u32.to_ne_bytes());
+        message.extend_from_slice(&123_456_789u64.to_ne_bytes());
+        for field in data {
+            message.extend_from_slice(&field.to_ne_bytes());
+        }
+        message
+    }
+
+    #[test]
+    fn process_events() {
+        let cases = [
+            // parent_pid, parent_tgid, child_pid, child_tgid
+            (
+                message(
+                    CN_IDX_PROC,
+                    CN_VAL_PROC,
+                    PROC_EVENT_FORK,
+                    &[9801, 9800, 9865, 9865],
+                ),
+                Some(ProcEvent::Fork {
+                    parent_tgid: 9800,
+                    child_pid: 9865,
+                    child_tgid: 9865,
+                }),
+            ),
+            // a new thread
+            (
+                message(
+                    CN_IDX_PROC,
+                    CN_VAL_PROC,
+                    PROC_EVENT_FORK,
+                    &[9865, 9865, 9866, 9865],
+                ),
+                Some(ProcEvent::Fork {
+                    parent_tgid: 9865,
+                    child_pid: 9866,
+                    child_tgid: 9865,
+                }),
+            ),
+            // process_pid, process_tgid
+            (
+                message(CN_IDX_PROC, CN_VAL_PROC, PROC_EVENT_EXEC, &[9865, 9865]),
+                Some(ProcEvent::Exec {
+                    pid: 9865,
+                    tgid: 9865,
+                }),
+            ),
+            // process_pid, process_tgid, exit_code, exit_signal
+            (
+                message(
+                    CN_IDX_PROC,
+                    CN_VAL_PROC,
+                    PROC_EVENT_EXIT,
+                    &[9866, 9865, 9, 17],
+                ),
+                Some(ProcEvent::Exit {
+                    pid: 9866,
+                    tgid: 9865,
+                }),
+            ),
+            // PROC_EVENT_UID
+            (
+                message(CN_IDX_PROC, CN_VAL_PROC, 0x0000_0004, &[9865, 9865, 0, 0]),
+                Some(ProcEvent::Other),
+            ),
+            // the messages of the other connectors
+            (
+                message(CN_IDX_PROC + 1, CN_VAL_PROC, PROC_EVENT_EXEC, &[9865, 9865]),
+                None,
+            ),
+            (
+                message(CN_IDX_PROC, CN_VAL_PROC + 1, PROC_EVENT_EXEC, &[9865, 9865]),
+                None,
+            ),
+        ];
+
+        for (i, (message, event)) in cases.iter().enumerate() {
+            assert_eq!(&parse_message(message), event, "case {}", i);
+        }
+    }
+
+    #[test]
+    fn truncated_messages() {
+        let fork = message(
+            CN_IDX_PROC,
+            CN_VAL_PROC,
+            PROC_EVENT_FORK,
+            &[9801, 9800, 9865, 9865],
+        );
+        assert!(parse_message(&fork).is_some());
+
+        // cut in the cn_msg, the proc_event header and the event data
+        for length in [0, NLMSG_HEADER_SIZE + 6, EVENTS_OFFSET + 2, fork.len() - 1] {
+            assert_eq!(parse_message(&fork[..length]), None, "length {}", length);
+        }
+    }
+}
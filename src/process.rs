@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time;
+
+use lru::LruCache;
+use procfs::process::Process;
+
+use crate::container;
+
+// the passwd of the host as seen by init (the DaemonSet shares the pid namespace of the host), else the own one
+const PASSWD_PATHS: &[&str] = &["/proc/1/root/etc/passwd", "/etc/passwd"];
+
+/*
+    Snapshot of a process taken while it is alive, the kernel only logs its pid and comm when it is killed:
+    pid 9865, ppid 9801, uid 1000 (deploy), comm java, exe /usr/lib/jvm/java-11/bin/java, cwd /srv/app,
+    cgroup /system.slice/app.service, cmdline "java -Xmx4g -jar app.jar", started at 1625487032000 (ms since epoch)
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessInfo {
+    pub cmdline: String,
+    pub exe: Option<String>,
+    pub cwd: Option<String>,
+    pub uid: u32,
+    pub user: Option<String>,
+    pub ppid: i32,
+    // milliseconds since epoch
+    pub start_time: Option<u64>,
+    pub comm: String,
+    pub cgroup: Option<String>,
+    pub container: Option<container::Container>,
+    // milliseconds since epoch, as soon as the proc connector tells or at the next refresh of the processes
+    pub exit_time: Option<u64>,
+}
+
+struct Users {
+    path: &'static str,
+    modified: time::SystemTime,
+    names: HashMap<u32, String>,
+}
+
+// the passwd file is parsed again only when it changes, every process of the host is resolved with it
+static USERS: Mutex<Option<Users>> = Mutex::new(None);
+
+fn parse_passwd(content: &str) -> HashMap<u32, String> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let uid = fields.nth(1)?.parse::<u32>().ok()?;
+            Some((uid, name.to_string()))
+        })
+        .collect()
+}
+
+pub fn get_username(uid: u32) -> Option<String> {
+    let (path, modified) = match PASSWD_PATHS.iter().find_map(|path| {
+        fs::metadata(path)
+            .and_then(|m| m.modified())
+            .ok()
+            .map(|modified| (*path, modified))
+    }) {
+        Some(passwd) => passwd,
+        None => {
+            debug!("Could not read any of {}", PASSWD_PATHS.join(", "));
+            return None;
+        }
+    };
+
+    let mut users = match USERS.lock() {
+        Ok(users) => users,
+        Err(e) => {
+            error!("Could not acquire the users table lock!. Error: {}", e);
+            return None;
+        }
+    };
+
+    if users.as_ref().map(|u| (u.path, u.modified)) != Some((path, modified)) {
+        let names = match fs::read_to_string(path) {
+            Ok(content) => parse_passwd(&content),
+            Err(e) => {
+                debug!("Could not read {}: {}", path, e);
+                return None;
+            }
+        };
+        *users = Some(Users {
+            path,
+            modified,
+            names,
+        });
+    }
+
+    users.as_ref().and_then(|u| u.names.get(&uid).cloned())
+}
+
+fn get_start_time(process: &Process) -> Option<u64> {
+    let boot_time = procfs::boot_time_secs().ok()?;
+    let ticks = procfs::ticks_per_second().ok()? as u64;
+    Some(boot_time * 1000 + process.stat.starttime * 1000 / ticks)
+}
+
+// the path in the cgroup v1 memory hierarchy, or in the unified one when memory is not a v1 controller
+fn get_cgroup(process: &Process) -> Option<String> {
+    let cgroups = process.cgroups().ok()?;
+
+    cgroups
+        .iter()
+        .find(|c| c.controllers.iter().any(|c| c == "memory"))
+        .or_else(|| cgroups.iter().find(|c| c.hierarchy == 0))
+        .map(|c| c.pathname.clone())
+}
+
+impl ProcessInfo {
+    pub fn read(process: &Process) -> ProcessInfo {
+        let cgroup = get_cgroup(process);
+
+        ProcessInfo {
+            cmdline: match process.cmdline() {
+                Ok(cmdline) => cmdline.join(" "),
+                Err(error) => error.to_string(),
+            },
+            exe: process.exe().ok().map(|p| p.to_string_lossy().to_string()),
+            cwd: process.cwd().ok().map(|p| p.to_string_lossy().to_string()),
+            uid: process.owner,
+            user: get_username(process.owner),
+            ppid: process.stat.ppid,
+            start_time: get_start_time(process),
+            comm: process.stat.comm.clone(),
+            container: cgroup.as_deref().and_then(container::parse_cgroup_path),
+            cgroup,
+            exit_time: None,
+        }
+    }
+}
+
+/// Cache a new snapshot of a process, keeping the parent it had when it was first seen:
+/// the orphans are reparented to init (or a subreaper) and their creator would be lost
+pub fn cache(processes: &mut LruCache<i32, ProcessInfo>, pid: i32, mut process: ProcessInfo) {
+    if let Some(previous) = processes.peek(&pid) {
+        if previous.start_time == process.start_time {
+            process.ppid = previous.ppid;
+        }
+    }
+
+    processes.put(pid, process);
+}
+
+/// The cached processes (the exited ones included) in a cgroup or in one of its descendants, oldest first
+pub fn cgroup_members(
+    processes: &LruCache<i32, ProcessInfo>,
+    cgroup: &str,
+) -> Vec<(i32, ProcessInfo)> {
+    let cgroup = cgroup.trim_end_matches('/');
+    let mut members: Vec<(i32, ProcessInfo)> = processes
+        .iter()
+        .filter(|(_, process)| match process.cgroup.as_deref() {
+            Some(path) => path
+                .strip_prefix(cgroup)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/')),
+            None => false,
+        })
+        .map(|(pid, process)| (*pid, process.clone()))
+        .collect();
+
+    members.sort_by_key(|(pid, process)| (process.start_time, *pid));
+    members
+}
+
+/// Remember when a cached process exited, it stays cached since its kill may be reported afterwards
+pub fn exited(processes: &mut LruCache<i32, ProcessInfo>, pid: i32) {
+    if let Some(process) = processes.peek_mut(&pid) {
+        process.exit_time = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .ok()
+            .map(|t| t.as_millis() as u64);
+    }
+}
+
+// a chain longer than this is a loop made by reused pids
+const MAX_ANCESTORS: usize = 64;
+
+/// The parent, grandparent, ... up to init, as known by the cache (the exited ones included).
+/// The chain stops at the first ancestor that is not cached or whose pid was reused by a younger process.
+pub fn ancestry(
+    processes: &LruCache<i32, ProcessInfo>,
+    process: &ProcessInfo,
+) -> Vec<(i32, Option<ProcessInfo>)> {
+    let mut ancestors = Vec::new();
+    let mut child = process;
+    let mut pid = process.ppid;
+
+    while pid > 0 && ancestors.len() < MAX_ANCESTORS {
+        match processes.peek(&pid) {
+            Some(parent) if parent.start_time <= child.start_time => {
+                ancestors.push((pid, Some(parent.clone())));
+                child = parent;
+                pid = parent.ppid;
+            }
+            _ => {
+                ancestors.push((pid, None));
+                break;
+            }
+        }
+    }
+
+    ancestors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(comm: &str, ppid: i32, start_time: u64) -> ProcessInfo {
+        ProcessInfo {
+            cmdline: comm.to_string(),
+            exe: None,
+            cwd: None,
+            uid: 0,
+            user: None,
+            ppid,
+            start_time: Some(start_time),
+            comm: comm.to_string(),
+            cgroup: None,
+            container: None,
+            exit_time: None,
+        }
+    }
+
+    #[test]
+    fn passwd_users() {
+        let passwd = "\
+# /etc/passwd of the host
+root:x:0:0:root:/root:/bin/bash
+daemon:x:1:1:daemon:/usr/sbin:/usr/sbin/nologin
+
+deploy:x:1000:1000:Deploy,,,:/home/deploy:/bin/bash
+broken
+nobody:x:-2:65534:nobody:/nonexistent:/usr/sbin/nologin
+ldap:x:uid:100::/:
++::::::
+";
+
+        let users = parse_passwd(passwd);
+        assert_eq!(users.len(), 3);
+        assert_eq!(users.get(&0).map(String::as_str), Some("root"));
+        assert_eq!(users.get(&1).map(String::as_str), Some("daemon"));
+        assert_eq!(users.get(&1000).map(String::as_str), Some("deploy"));
+    }
+
+    fn ancestor_pids(ancestors: &[(i32, Option<ProcessInfo>)]) -> Vec<(i32, bool)> {
+        ancestors
+            .iter()
+            .map(|(pid, process)| (*pid, process.is_some()))
+            .collect()
+    }
+
+    #[test]
+    fn ancestry_up_to_init() {
+        let mut processes = LruCache::new(16);
+        processes.put(1, process("systemd", 0, 1000));
+        processes.put(900, process("containerd-shim", 1, 2000));
+        // the shell exited, it is still cached
+        let mut shell = process("sh", 900, 3000);
+        shell.exit_time = Some(5000);
+        processes.put(950, shell);
+        let java = process("java", 950, 4000);
+
+        let ancestors = ancestry(&processes, &java);
+        assert_eq!(
+            ancestor_pids(&ancestors),
+            vec![(950, true), (900, true), (1, true)]
+        );
+        assert_eq!(ancestors[0].1.as_ref().unwrap().exit_time, Some(5000));
+    }
+
+    #[test]
+    fn ancestry_of_unknown_parents() {
+        let mut processes = LruCache::new(16);
+        processes.put(1, process("systemd", 0, 1000));
+        processes.put(900, process("containerd-shim", 1, 2000));
+
+        // not cached
+        let java = process("java", 950, 4000);
+        assert_eq!(
+            ancestor_pids(&ancestry(&processes, &java)),
+            vec![(950, false)]
+        );
+
+        // the parent exited and its pid was reused by a process started after the child
+        processes.put(950, process("curl", 1, 6000));
+        assert_eq!(
+            ancestor_pids(&ancestry(&processes, &java)),
+            vec![(950, false)]
+        );
+
+        // a parent started in the same millisecond is still the parent
+        processes.put(950, process("sh", 900, 4000));
+        assert_eq!(
+            ancestor_pids(&ancestry(&processes, &java)),
+            vec![(950, true), (900, true), (1, true)]
+        );
+    }
+
+    #[test]
+    fn ancestry_loop() {
+        // two reused pids started in the same millisecond, each one the parent of the other
+        let mut processes = LruCache::new(16);
+        processes.put(950, process("sh", 960, 4000));
+        processes.put(960, process("sh", 950, 4000));
+        let java = process("java", 950, 4000);
+
+        let ancestors = ancestry(&processes, &java);
+        assert_eq!(ancestors.len(), MAX_ANCESTORS);
+        assert!(ancestors.iter().all(|(_, process)| process.is_some()));
+    }
+
+    #[test]
+    fn orphan_keeps_its_parent() {
+        let mut processes = LruCache::new(16);
+        cache(&mut processes, 950, process("sh", 900, 3000));
+
+        // reparented to init once its parent exited
+        cache(&mut processes, 950, process("sh", 1, 3000));
+        assert_eq!(processes.peek(&950).unwrap().ppid, 900);
+
+        // the pid was reused by another process
+        cache(&mut processes, 950, process("curl", 1, 6000));
+        assert_eq!(processes.peek(&950).unwrap().ppid, 1);
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+
+use crate::events;
+use crate::pipeline;
+use crate::process;
+
+/*
+    Memory pressure stall information, from /proc/pressure/memory or the memory.pressure file of a cgroup v2:
+    some avg10=0.00 avg60=0.00 avg300=0.00 total=0
+    full avg10=0.00 avg60=0.00 avg300=0.00 total=0
+    "some" is the share of time at least one task was stalled on memory, "full" the share of time all of them were.
+*/
+
+pub const SYSTEM_PRESSURE_PATH: &str = "/proc/pressure/memory";
+
+const THRESHOLD_METRICS: &[&str] = &["some_avg10", "some_avg60", "full_avg10", "full_avg60"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Threshold {
+    pub metric: String,
+    pub value: f64,
+}
+
+/// A threshold has the form metric=percentage, e.g. some_avg10=20
+pub fn parse_threshold(spec: &str) -> Result<Threshold, String> {
+    let (metric, value) = match spec.split_once('=') {
+        Some((metric, value)) => (metric.trim(), value.trim()),
+        None => {
+            return Err(format!(
+                "{} must have the form metric=percentage, e.g. some_avg10=20",
+                spec
+            ))
+        }
+    };
+
+    if !THRESHOLD_METRICS.contains(&metric) {
+        return Err(format!(
+            "{} is not a pressure metric, the available ones are {}",
+            metric,
+            THRESHOLD_METRICS.join("/")
+        ));
+    }
+
+    match value.parse::<f64>() {
+        Ok(value) => Ok(Threshold {
+            metric: metric.to_string(),
+            value,
+        }),
+        Err(e) => Err(format!(
+            "Invalid percentage {} for {}: {}",
+            value, metric, e
+        )),
+    }
+}
+
+/// The averages of a pressure file keyed like the thresholds (some_avg10, full_avg300, ...)
+pub fn parse_pressure(content: &str) -> BTreeMap<String, f64> {
+    let mut pressure = BTreeMap::new();
+
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let kind = match fields.next() {
+            Some(kind) => kind,
+            None => continue,
+        };
+
+        for field in fields {
+            match field.split_once('=') {
+                Some((window, value)) if window.starts_with("avg") => {
+                    if let Ok(value) = value.parse::<f64>() {
+                        pressure.insert(format!("{}_{}", kind, window), value);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pressure
+}
+
+pub struct PsiWatcher {
+    // the cgroup v2 directories to watch besides the whole system
+    pub cgroups: Vec<String>,
+    pub thresholds: Vec<Threshold>,
+    pub processes: Arc<Mutex<LruCache<i32, process::ProcessInfo>>>,
+    pub top_consumers: usize,
+    pub sink: pipeline::Sink,
+    // sources above a threshold are reported once, until their pressure goes back down
+    high: HashSet<String>,
+    unreadable: HashSet<String>,
+}
+
+impl PsiWatcher {
+    pub fn new(
+        cgroups: Vec<String>,
+        thresholds: Vec<Threshold>,
+        processes: Arc<Mutex<LruCache<i32, process::ProcessInfo>>>,
+        top_consumers: usize,
+        sink: pipeline::Sink,
+    ) -> PsiWatcher {
+        PsiWatcher {
+            cgroups,
+            thresholds,
+            processes,
+            top_consumers,
+            sink,
+            high: HashSet::new(),
+            unreadable: HashSet::new(),
+        }
+    }
+
+    /// Read the pressure of every source and report the ones that crossed a threshold
+    pub fn check(&mut self) {
+        self.check_source(SYSTEM_PRESSURE_PATH.to_string(), None);
+
+        for cgroup in self.cgroups.clone() {
+            self.check_source(format!("{}/memory.pressure", cgroup), Some(cgroup));
+        }
+    }
+
+    fn check_source(&mut self, path: String, cgroup: Option<String>) {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => {
+                self.unreadable.remove(&path);
+                content
+            }
+            Err(e) => {
+                // a missing file stays missing (e.g. a kernel without PSI), it is logged only once
+                if self.unreadable.insert(path.clone()) {
+                    error!("Could not read the memory pressure from {}: {}", path, e);
+                }
+                return;
+            }
+        };
+
+        let pressure = parse_pressure(&content);
+        let exceeded = match self.crossed_thresholds(&path, &pressure) {
+            Some(exceeded) => exceeded,
+            None => return,
+        };
+
+        let consumers = self.top_memory_consumers(cgroup.as_deref());
+        let pressure_event = events::build_pressure_event(
+            &path,
+            cgroup.as_deref(),
+            &pressure,
+            &exceeded.iter().collect::<Vec<&Threshold>>(),
+            consumers,
+        );
+        warn!("Memory pressure high: {}", &pressure_event);
+
+        // the pressure is not an oom, the selected scopes do not apply to it
+        self.sink.emit(&pressure_event);
+    }
+
+    /// The thresholds a source went above, None while it is still reported or under all of them
+    fn crossed_thresholds(
+        &mut self,
+        path: &str,
+        pressure: &BTreeMap<String, f64>,
+    ) -> Option<Vec<Threshold>> {
+        let exceeded: Vec<Threshold> = self
+            .thresholds
+            .iter()
+            .filter(|t| {
+                pressure
+                    .get(&t.metric)
+                    .is_some_and(|value| *value >= t.value)
+            })
+            .cloned()
+            .collect();
+
+        if exceeded.is_empty() {
+            if self.high.remove(path) {
+                info!(
+                    "The memory pressure of {} went back under the thresholds",
+                    path
+                );
+            }
+            return None;
+        }
+
+        if !self.high.insert(path.to_string()) {
+            return None;
+        }
+        Some(exceeded)
+    }
+
+    fn cached_pids(&self) -> Vec<i32> {
+        match self.processes.lock() {
+            Ok(procs) => procs.iter().map(|(pid, _)| *pid).collect(),
+            Err(e) => {
+                error!("Could not acquire the process table lock in the memory-pressure-watcher thread!. Error: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn top_memory_consumers(&self, cgroup: Option<&str>) -> Vec<serde_json::Value> {
+        // the processes of a cgroup are listed by the cgroup itself, the cache may not know them yet
+        let pids: Vec<i32> = match cgroup {
+            Some(cgroup) => match fs::read_to_string(format!("{}/cgroup.procs", cgroup)) {
+                Ok(content) => content
+                    .lines()
+                    .filter_map(|pid| pid.trim().parse::<i32>().ok())
+                    .collect(),
+                Err(e) => {
+                    error!(
+                        "Could not list the processes of the cgroup {}: {}",
+                        cgroup, e
+                    );
+                    Vec::new()
+                }
+            },
+            None => self.cached_pids(),
+        };
+
+        // /proc is read without holding the lock, the kernel log reader needs the cache during an oom
+        let mut consumers: Vec<(i32, String, i64)> = pids
+            .into_iter()
+            .filter_map(|pid| {
+                procfs::process::Process::new(pid)
+                    .ok()
+                    .map(|p| (pid, p.stat.comm, p.stat.rss))
+            })
+            .collect();
+        consumers.sort_by_key(|(_, _, rss)| std::cmp::Reverse(*rss));
+        consumers.truncate(self.top_consumers);
+
+        let procs = match self.processes.lock() {
+            Ok(procs) => Some(procs),
+            Err(e) => {
+                error!("Could not acquire the process table lock in the memory-pressure-watcher thread!. Error: {}", e);
+                None
+            }
+        };
+
+        consumers
+            .into_iter()
+            .map(|(pid, comm, rss)| {
+                events::build_consumer(
+                    pid,
+                    &comm,
+                    rss,
+                    procs
+                        .as_ref()
+                        .and_then(|p| p.peek(&pid))
+                        .map(|p| &p.cmdline),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::notifiers;
+
+    const PRESSURE: &str = "\
+some avg10=42.17 avg60=18.03 avg300=4.51 total=123456789
+full avg10=12.50 avg60=3.20 avg300=0.81 total=23456789
+";
+
+    fn threshold(metric: &str, value: f64) -> Threshold {
+        Threshold {
+            metric: metric.to_string(),
+            value,
+        }
+    }
+
+    #[test]
+    fn thresholds() {
+        let cases = [
+            ("some_avg10=40", Some(threshold("some_avg10", 40.0))),
+            ("some_avg60=12.5", Some(threshold("some_avg60", 12.5))),
+            (" full_avg10 = 5 ", Some(threshold("full_avg10", 5.0))),
+            ("full_avg60=0", Some(threshold("full_avg60", 0.0))),
+            ("some_avg300=10", None),
+            ("full_total=10", None),
+            ("some_avg10", None),
+            ("some_avg10=", None),
+            ("some_avg10=high", None),
+            ("=40", None),
+        ];
+
+        for (spec, threshold) in &cases {
+            assert_eq!(&parse_threshold(spec).ok(), threshold, "spec {}", spec);
+        }
+    }
+
+    #[test]
+    fn pressure_file() {
+        let pressure = parse_pressure(PRESSURE);
+        let expected: BTreeMap<String, f64> = [
+            ("some_avg10", 42.17),
+            ("some_avg60", 18.03),
+            ("some_avg300", 4.51),
+            ("full_avg10", 12.5),
+            ("full_avg60", 3.2),
+            ("full_avg300", 0.81),
+        ]
+        .iter()
+        .map(|(metric, value)| (metric.to_string(), *value))
+        .collect();
+        assert_eq!(pressure, expected);
+
+        // kernels before 5.13 have no full line for the whole system
+        assert_eq!(parse_pressure(PRESSURE.lines().next().unwrap()).len(), 3);
+        assert!(parse_pressure("").is_empty());
+    }
+
+    #[test]
+    fn reported_once_until_back_down() {
+        let mut watcher = PsiWatcher::new(
+            Vec::new(),
+            vec![threshold("some_avg10", 40.0), threshold("full_avg60", 10.0)],
+            Arc::new(Mutex::new(LruCache::new(16))),
+            0,
+            pipeline::Sink {
+                notifiers: notifiers::Notifiers::default(),
+                oom_scopes: Vec::new(),
+                kubernetes: None,
+            },
+        );
+        let high = parse_pressure(PRESSURE);
+        let low = parse_pressure("some avg10=3.00 avg60=1.00 avg300=0.20 total=1234");
+
+        assert_eq!(
+            watcher.crossed_thresholds(SYSTEM_PRESSURE_PATH, &high),
+            Some(vec![threshold("some_avg10", 40.0)])
+        );
+        assert_eq!(
+            watcher.crossed_thresholds(SYSTEM_PRESSURE_PATH, &high),
+            None
+        );
+        // every source is reported on its own
+        assert!(watcher
+            .crossed_thresholds("/sys/fs/cgroup/kubepods.slice/memory.pressure", &high)
+            .is_some());
+
+        assert_eq!(watcher.crossed_thresholds(SYSTEM_PRESSURE_PATH, &low), None);
+        assert_eq!(
+            watcher.crossed_thresholds(SYSTEM_PRESSURE_PATH, &high),
+            Some(vec![threshold("some_avg10", 40.0)])
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::time;
+
+use regex::Regex;
+
+use crate::kmsg;
+use crate::pipeline;
+
+/*
+    Replay feeds a saved kernel log through the same pipeline of the live kernel log reader.
+    Examples of the supported formats:
+    6,1234,5678901234,-;Out of memory: Killed process 9865 (oom_trigger) ...    (cat /dev/kmsg)
+    [ 5678.901234] Out of memory: Killed process 9865 (oom_trigger) ...         (dmesg)
+    [Thu Oct 16 12:00:00 2026] Out of memory: Killed process 9865 (oom_trigger) ...  (dmesg -T)
+    Oct 16 12:00:00 myhost kernel: Out of memory: Killed process 9865 (oom_trigger) ...  (journalctl -k)
+*/
+
+fn is_kmsg_dictionary(line: &str) -> bool {
+    // dictionary lines of /dev/kmsg records look like " SUBSYSTEM=pci"
+    line.starts_with(' ')
+        && line
+            .trim_start()
+            .split(|c: char| c.is_whitespace())
+            .next()
+            .is_some_and(|field| {
+                field.contains('=') && field.starts_with(|c: char| c.is_ascii_uppercase())
+            })
+}
+
+fn strip_timestamp(line: &str) -> (&str, time::Duration) {
+    let inner = match line
+        .strip_prefix('[')
+        .and_then(|rest| rest.find(']').map(|end| (&rest[..end], &rest[end + 1..])))
+    {
+        Some(inner) => inner,
+        None => return (line, time::Duration::from_secs(0)),
+    };
+
+    // the rows of the oom task table are bracketed too, but they hold a pid and not a timestamp
+    match inner.0.trim().parse::<f64>() {
+        Ok(seconds) if inner.0.contains('.') => {
+            (inner.1.trim_start(), time::Duration::from_secs_f64(seconds))
+        }
+        Err(_) if inner.0.contains(':') => (inner.1.trim_start(), time::Duration::from_secs(0)),
+        _ => (line, time::Duration::from_secs(0)),
+    }
+}
+
+// the date and host printed by journalctl before the kernel messages
+fn journal_prefix() -> Regex {
+    Regex::new(r"^(\w{3} [ \d]\d \d\d:\d\d:\d\d )?\S+ kernel: ").unwrap() // this is a valid regex
+}
+
+pub fn parse_dump_line(line: &str, journal_prefix: &Regex) -> Option<(String, time::Duration)> {
+    if let Some(record) = kmsg::parse_record(line) {
+        return Some((record.message, record.timestamp_from_system_start));
+    }
+
+    if is_kmsg_dictionary(line) {
+        return None;
+    }
+
+    let (message, timestamp) = strip_timestamp(line);
+    let message = match journal_prefix.find(message) {
+        Some(prefix) => &message[prefix.end()..],
+        None => message,
+    };
+
+    Some((message.to_string(), timestamp))
+}
+
+/// Feed every line of a kernel log dump to the pipeline, the number of lines read is returned
+pub fn replay(path: &str, pipeline: &mut pipeline::Pipeline) -> Result<usize, String> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            return Err(format!(
+                "Could not open the kernel log dump {}: {}",
+                path, e
+            ))
+        }
+    };
+
+    let journal_prefix = journal_prefix();
+    let mut lines_read = 0;
+
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                return Err(format!(
+                    "Could not read the kernel log dump {}: {}",
+                    path, e
+                ))
+            }
+        };
+        lines_read += 1;
+
+        if let Some((message, timestamp)) = parse_dump_line(&line, &journal_prefix) {
+            for message_line in message.lines() {
+                pipeline.process_line(message_line, timestamp);
+            }
+        }
+    }
+
+    Ok(lines_read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump_formats() {
+        let journal_prefix = journal_prefix();
+        let message = "Out of memory: Killed process 9865 (oom_trigger) total-vm:7468696kB";
+        let formats = vec![
+            (
+                format!("6,1234,5678901234,-;{}", message),
+                time::Duration::from_micros(5678901234),
+            ),
+            (
+                format!("[ 5678.901234] {}", message),
+                time::Duration::from_micros(5678901234),
+            ),
+            (
+                format!("[Thu Oct 16 12:00:00 2026] {}", message),
+                time::Duration::from_secs(0),
+            ),
+            (
+                format!("Oct 16 12:00:00 myhost kernel: {}", message),
+                time::Duration::from_secs(0),
+            ),
+            (
+                format!("myhost kernel: {}", message),
+                time::Duration::from_secs(0),
+            ),
+        ];
+
+        for (i, (line, timestamp)) in formats.into_iter().enumerate() {
+            assert_eq!(
+                parse_dump_line(&line, &journal_prefix),
+                Some((message.to_string(), timestamp)),
+                "format {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn dump_task_rows_and_dictionary() {
+        let journal_prefix = journal_prefix();
+
+        // a task table row without timestamp is not mistaken for a dmesg one
+        let row = "[   4242]     0  4242  1049619   981160  7925760        0             0 stress";
+        assert_eq!(
+            parse_dump_line(row, &journal_prefix),
+            Some((row.to_string(), time::Duration::from_secs(0)))
+        );
+        assert_eq!(
+            parse_dump_line(&format!("[ 5678.900430] {}", row), &journal_prefix),
+            Some((row.to_string(), time::Duration::from_micros(5678900430)))
+        );
+
+        assert_eq!(parse_dump_line(" SUBSYSTEM=pci", &journal_prefix), None);
+    }
+}
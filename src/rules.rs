@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+
+use regex::Regex;
+
+/*
+    Rules turn arbitrary kernel log lines into events, the pid and comm named groups of the regex
+    are used to enrich the event with the command line of the process.
+    Examples of the lines matched by the builtin rules:
+    a.out[4242]: segfault at 0 ip 000055d0c8e5e139 sp 00007ffd2c3c9b90 error 6 in a.out[55d0c8e5e000+1000]
+    traps: java[4242] general protection fault ip:7f3e2c1b4d2a sp:7ffd2c3c9b90 error:0 in libc-2.31.so[7f3e2c14f000+178000]
+    INFO: task jbd2/sda1-8:4242 blocked for more than 120 seconds.
+    watchdog: BUG: soft lockup - CPU#1 stuck for 23s! [stress:4242]
+    kworker/u8:2: page allocation failure: order:4, mode:0x40cc0(GFP_KERNEL|__GFP_COMP), nodemask=(null)
+*/
+
+const BUILTIN_RULES: &[(&str, &str)] = &[
+    ("segfault", r"^(?P<comm>.+)\[(?P<pid>\d+)\]: segfault at"),
+    (
+        "general_protection",
+        r"^traps: (?P<comm>.+)\[(?P<pid>\d+)\] general protection",
+    ),
+    (
+        "hung_task",
+        r"INFO: task (?P<comm>.+):(?P<pid>\d+) blocked for more than",
+    ),
+    (
+        "soft_lockup",
+        r"BUG: soft lockup - CPU#\d+ stuck for \d+s! \[(?P<comm>.+):(?P<pid>\d+)\]",
+    ),
+    (
+        "page_allocation_failure",
+        r"^(?P<comm>\S+): page allocation failure: order:(?P<order>\d+)",
+    ),
+];
+
+pub struct Rule {
+    pub name: String,
+    regex: Regex,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleMatch {
+    pub rule: String,
+    pub pid: Option<i32>,
+    pub comm: Option<String>,
+    // every other named group of the regex
+    pub fields: BTreeMap<String, String>,
+}
+
+/// A rule is either the name of a builtin rule or has the form name=regex
+pub fn parse_rule(spec: &str) -> Result<Rule, String> {
+    let (name, pattern) = match spec.split_once('=') {
+        Some((name, pattern)) => (name.trim(), pattern),
+        None => match BUILTIN_RULES.iter().find(|(name, _)| *name == spec.trim()) {
+            Some((name, pattern)) => (*name, *pattern),
+            None => {
+                return Err(format!(
+                    "{} is not a builtin rule, the available ones are {}",
+                    spec,
+                    BUILTIN_RULES
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<&str>>()
+                        .join("/")
+                ))
+            }
+        },
+    };
+
+    match Regex::new(pattern) {
+        Ok(regex) => Ok(Rule {
+            name: name.to_string(),
+            regex,
+        }),
+        Err(e) => Err(format!("Invalid regex for the rule {}: {}", name, e)),
+    }
+}
+
+impl Rule {
+    pub fn matches(&self, message: &str) -> Option<RuleMatch> {
+        let captures = self.regex.captures(message)?;
+        let mut rule_match = RuleMatch {
+            rule: self.name.clone(),
+            pid: None,
+            comm: None,
+            fields: BTreeMap::new(),
+        };
+
+        for group in self.regex.capture_names().flatten() {
+            let value = match captures.name(group) {
+                Some(value) => value.as_str(),
+                None => continue,
+            };
+
+            match group {
+                "pid" => rule_match.pid = value.parse::<i32>().ok(),
+                "comm" => rule_match.comm = Some(value.to_string()),
+                _ => {
+                    rule_match
+                        .fields
+                        .insert(group.to_string(), value.to_string());
+                }
+            }
+        }
+
+        Some(rule_match)
+    }
+}
+
+/// The match of the first rule, in the order they were given, matching the message
+pub fn first_match(rules: &[Rule], message: &str) -> Option<RuleMatch> {
+    rules.iter().find_map(|rule| rule.matches(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule_match(rule: &str, pid: Option<i32>, comm: &str, fields: &[(&str, &str)]) -> RuleMatch {
+        RuleMatch {
+            rule: rule.to_string(),
+            pid,
+            comm: Some(comm.to_string()),
+            fields: fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn builtin_rules() {
+        let cases = [
+            (
+                "segfault",
+                "a.out[4242]: segfault at 0 ip 000055d0c8e5e139 sp 00007ffd2c3c9b90 error 6 in a.out[55d0c8e5e000+1000]",
+                rule_match("segfault", Some(4242), "a.out", &[]),
+            ),
+            (
+                "general_protection",
+                "traps: java[4242] general protection fault ip:7f3e2c1b4d2a sp:7ffd2c3c9b90 error:0 in libc-2.31.so[7f3e2c14f000+178000]",
+                rule_match("general_protection", Some(4242), "java", &[]),
+            ),
+            (
+                "hung_task",
+                "INFO: task jbd2/sda1-8:4242 blocked for more than 120 seconds.",
+                rule_match("hung_task", Some(4242), "jbd2/sda1-8", &[]),
+            ),
+            (
+                "soft_lockup",
+                "watchdog: BUG: soft lockup - CPU#1 stuck for 23s! [stress:4242]",
+                rule_match("soft_lockup", Some(4242), "stress", &[]),
+            ),
+            (
+                "page_allocation_failure",
+                "kworker/u8:2: page allocation failure: order:4, mode:0x40cc0(GFP_KERNEL|__GFP_COMP), nodemask=(null)",
+                rule_match("page_allocation_failure", None, "kworker/u8:2", &[("order", "4")]),
+            ),
+        ];
+
+        let rules: Vec<Rule> = cases
+            .iter()
+            .map(|(name, _, _)| parse_rule(name).unwrap())
+            .collect();
+        for (name, line, expected) in &cases {
+            assert_eq!(
+                first_match(&rules, line).as_ref(),
+                Some(expected),
+                "rule {}",
+                name
+            );
+        }
+
+        assert_eq!(
+            first_match(&rules, "Out of memory: Killed process 4242 (java)"),
+            None
+        );
+        assert!(parse_rule("oom_kill").is_err());
+    }
+
+    #[test]
+    fn custom_rules() {
+        let rule = parse_rule(
+            r"mydriver=^mydriver: reset requested by (?P<comm>\S+)\[(?P<pid>\d+)\] on (?P<device>\w+)",
+        )
+        .unwrap();
+        assert_eq!(rule.name, "mydriver");
+        assert_eq!(
+            rule.matches("mydriver: reset requested by fwupd[4242] on nvme0"),
+            Some(rule_match(
+                "mydriver",
+                Some(4242),
+                "fwupd",
+                &[("device", "nvme0")]
+            ))
+        );
+        assert_eq!(
+            rule.matches("otherdriver: reset requested by fwupd[4242] on nvme0"),
+            None
+        );
+
+        // the regex may have any groups, or none
+        let rule = parse_rule("thermal=CPU\\d+: Package temperature above threshold").unwrap();
+        assert_eq!(
+            rule.matches("CPU3: Package temperature above threshold, cpu clock throttled"),
+            Some(RuleMatch {
+                rule: "thermal".to_string(),
+                pid: None,
+                comm: None,
+                fields: BTreeMap::new(),
+            })
+        );
+
+        assert!(parse_rule("broken=task (?P<comm>.+").is_err());
+        assert!(parse_rule("broken=(?P<pid>\\d+) (?P<pid>\\d+)").is_err());
+    }
+
+    #[test]
+    fn first_rule_wins() {
+        let rules = vec![
+            parse_rule("segfault").unwrap(),
+            parse_rule(
+                r"crash=^(?P<comm>.+)\[(?P<pid>\d+)\]: (?P<kind>segfault|general protection)",
+            )
+            .unwrap(),
+        ];
+        let line = "a.out[4242]: segfault at 0 ip 000055d0c8e5e139 sp 00007ffd2c3c9b90 error 6";
+
+        assert_eq!(
+            first_match(&rules, line).map(|m| m.rule),
+            Some("segfault".to_string())
+        );
+        let reversed: Vec<Rule> = rules.into_iter().rev().collect();
+        assert_eq!(
+            first_match(&reversed, line),
+            Some(rule_match(
+                "crash",
+                Some(4242),
+                "a.out",
+                &[("kind", "segfault")]
+            ))
+        );
+    }
+}
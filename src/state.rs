@@ -0,0 +1,258 @@
+use std::fs;
+use std::time;
+
+use serde_json::json;
+
+/*
+    The state file keeps the sequence number of the last kernel log record processed, so a restarted daemon
+    resumes from there instead of skipping what was logged while it was down.
+    Sequence numbers restart at every boot, the boot id tells if the saved one still applies.
+    The oom_kill counter of /proc/vmstat saved with it (the kills logged up to that record) is where the cross-check
+    of the resumed kills starts from:
+    {"boot_id": "0d3d5a4c-8f5e-4a43-9a2e-0d1f6c1b2a3e", "sequence": 123456, "oom_kills": 3}
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    pub boot_id: String,
+    pub sequence: u64,
+    // missing on kernels without the oom_kill counter and in the state files of older versions
+    pub oom_kills: Option<u64>,
+}
+
+pub fn get_boot_id() -> Result<String, String> {
+    match fs::read_to_string("/proc/sys/kernel/random/boot_id") {
+        Ok(boot_id) => Ok(boot_id.trim().to_string()),
+        Err(e) => Err(format!(
+            "Could not read /proc/sys/kernel/random/boot_id: {}",
+            e
+        )),
+    }
+}
+
+pub fn load(path: &str) -> Result<Option<State>, String> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Could not read the state file {}: {}", path, e)),
+    };
+
+    let state: serde_json::Value = match serde_json::from_str(&content) {
+        Ok(state) => state,
+        Err(e) => return Err(format!("Could not parse the state file {}: {}", path, e)),
+    };
+
+    match (state["boot_id"].as_str(), state["sequence"].as_u64()) {
+        (Some(boot_id), Some(sequence)) => Ok(Some(State {
+            boot_id: boot_id.to_string(),
+            sequence,
+            oom_kills: state["oom_kills"].as_u64(),
+        })),
+        _ => Err(format!("Invalid content of the state file {}", path)),
+    }
+}
+
+/// The saved state if it was saved during the current boot
+pub fn load_for_boot(path: &str, boot_id: &str) -> Result<Option<State>, String> {
+    match load(path)? {
+        Some(state) if state.boot_id == boot_id => Ok(Some(state)),
+        Some(_) => {
+            info!(
+                "The state file {} was saved during a previous boot, ignoring it",
+                path
+            );
+            Ok(None)
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn save(path: &str, state: &State) -> Result<(), String> {
+    let content = json!({ "boot_id": state.boot_id,
+                     "sequence": state.sequence,
+                     "oom_kills": state.oom_kills });
+
+    // write and rename, so a crash while saving never leaves a truncated state file behind
+    let tmp_path = format!("{}.tmp", path);
+    if let Err(e) = fs::write(&tmp_path, content.to_string()) {
+        return Err(format!(
+            "Could not write the state file {}: {}",
+            tmp_path, e
+        ));
+    }
+
+    match fs::rename(&tmp_path, path) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Could not replace the state file {}: {}", path, e)),
+    }
+}
+
+// saving at every record would mean a write for each kernel log line on chatty hosts
+const SAVE_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+pub struct Checkpoint {
+    path: String,
+    boot_id: String,
+    saved_sequence: Option<u64>,
+    last_save: time::Instant,
+    // the oom_kill counter when the kernel log was last read up to its end, with the kills handled by then
+    synced_oom_kills: Option<(u64, u64)>,
+}
+
+impl Checkpoint {
+    pub fn new(path: String, boot_id: String, saved_sequence: Option<u64>) -> Checkpoint {
+        Checkpoint {
+            path,
+            boot_id,
+            saved_sequence,
+            last_save: time::Instant::now(),
+            synced_oom_kills: None,
+        }
+    }
+
+    /// Record the oom_kill counter at a point where every record logged has been read, with every kill logged it counts
+    pub fn sync(&mut self, oom_kills: u64, handled: u64) {
+        self.synced_oom_kills = Some((oom_kills, handled));
+    }
+
+    /// Save the sequence number if it changed, at most once per interval unless forced.
+    /// The kernel counts a kill before logging it, so the counter read while records are still waiting to be read
+    /// may include kills logged after this one: the counter saved is the synced one plus the kills handled since
+    pub fn update(&mut self, sequence: u64, handled: u64, force: bool) {
+        if self.saved_sequence == Some(sequence)
+            || (!force && self.last_save.elapsed() < SAVE_INTERVAL)
+        {
+            return;
+        }
+
+        let state = State {
+            boot_id: self.boot_id.clone(),
+            sequence,
+            oom_kills: self
+                .synced_oom_kills
+                .map(|(oom_kills, synced)| oom_kills + handled.saturating_sub(synced)),
+        };
+        match save(&self.path, &state) {
+            Ok(_) => {
+                self.saved_sequence = Some(sequence);
+                self.last_save = time::Instant::now();
+            }
+            Err(e) => error!("{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOOT_ID: &str = "0d3d5a4c-8f5e-4a43-9a2e-0d1f6c1b2a3e";
+
+    // a directory of its own for every test, they run in parallel
+    fn state_path(test: &str) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("oom-notifier-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("state.json").to_string_lossy().to_string()
+    }
+
+    fn remove(path: &str) {
+        let _ = fs::remove_dir_all(std::path::Path::new(path).parent().unwrap());
+    }
+
+    #[test]
+    fn saved_and_loaded() {
+        let path = state_path("saved-and-loaded");
+        assert_eq!(load(&path), Ok(None));
+
+        let state = State {
+            boot_id: BOOT_ID.to_string(),
+            sequence: 123456,
+            oom_kills: Some(3),
+        };
+        save(&path, &state).unwrap();
+        assert_eq!(load(&path), Ok(Some(state.clone())));
+        assert_eq!(load_for_boot(&path, BOOT_ID), Ok(Some(state)));
+        // saved during a previous boot
+        assert_eq!(
+            load_for_boot(&path, "5b1f7e2a-3c4d-4e5f-8a9b-0c1d2e3f4a5b"),
+            Ok(None)
+        );
+
+        remove(&path);
+    }
+
+    #[test]
+    fn older_and_invalid_files() {
+        let path = state_path("older-and-invalid-files");
+
+        // the versions before the cross-check did not save the counter
+        fs::write(
+            &path,
+            format!(r#"{{"boot_id": "{}", "sequence": 42}}"#, BOOT_ID),
+        )
+        .unwrap();
+        assert_eq!(
+            load(&path),
+            Ok(Some(State {
+                boot_id: BOOT_ID.to_string(),
+                sequence: 42,
+                oom_kills: None,
+            }))
+        );
+
+        for content in &[
+            "",
+            r#"{"boot_id": "0d3d5a4c"#,
+            r#"{"boot_id": "0d3d5a4c"}"#,
+            r#"{"boot_id": "0d3d5a4c", "sequence": -1}"#,
+            r#"["0d3d5a4c", 42]"#,
+        ] {
+            fs::write(&path, content).unwrap();
+            assert!(load(&path).is_err(), "content {}", content);
+            assert!(
+                load_for_boot(&path, BOOT_ID).is_err(),
+                "content {}",
+                content
+            );
+        }
+
+        remove(&path);
+    }
+
+    #[test]
+    fn checkpoint_saves() {
+        let path = state_path("checkpoint-saves");
+        let mut checkpoint = Checkpoint::new(path.clone(), BOOT_ID.to_string(), Some(10));
+        let saved = |sequence, oom_kills| {
+            Ok(Some(State {
+                boot_id: BOOT_ID.to_string(),
+                sequence,
+                oom_kills,
+            }))
+        };
+
+        // the sequence number loaded is not saved again, even when forced
+        checkpoint.update(10, 0, true);
+        assert_eq!(load(&path), Ok(None));
+
+        // at most once per interval unless forced
+        checkpoint.update(11, 0, false);
+        assert_eq!(load(&path), Ok(None));
+        checkpoint.update(11, 0, true);
+        assert_eq!(load(&path), saved(11, None));
+        checkpoint.update(12, 0, false);
+        assert_eq!(load(&path), saved(11, None));
+
+        // the kills handled since the counter was synced are added to it
+        checkpoint.sync(7, 1);
+        checkpoint.update(13, 3, true);
+        assert_eq!(load(&path), saved(13, Some(9)));
+        checkpoint.sync(9, 3);
+        checkpoint.update(14, 3, true);
+        assert_eq!(load(&path), saved(14, Some(9)));
+
+        remove(&path);
+    }
+}
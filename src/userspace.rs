@@ -0,0 +1,361 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use lru::LruCache;
+use regex::Regex;
+
+use crate::events;
+use crate::incident;
+use crate::pipeline;
+use crate::process;
+
+/*
+    Userspace oom killers log their kills instead of the kernel, examples of the lines recognized:
+    sending SIGTERM to process 2876 uid 1000 "java": badness 910, VmRSS 7412 MiB                 (earlyoom 1.6)
+    sending SIGTERM to process 2876 uid 1000 "java": oom_score 910, VmRSS 7412 MiB, cmdline "..."  (earlyoom 1.7+)
+    Killed /system.slice/batch.service due to memory pressure for /system.slice being 62.13% > 50.00% for > 20s with reclaim activity    (systemd-oomd)
+*/
+
+const EARLYOOM_KILL: &str = r#"sending (?P<signal>SIG[A-Z]+) to process (?P<pid>\d+)(?: uid (?P<uid>\d+))? "(?P<comm>.*)": (?:badness|oom_score) (?P<score>-?\d+), VmRSS (?P<rss_mib>\d+) MiB"#;
+const SYSTEMD_OOMD_KILL: &str = r"Killed (?P<cgroup>/\S*) due to (?P<reason>.+)$";
+
+// the journal entries of the userspace killers, only the new ones are followed
+const JOURNALCTL_ARGS: &[&str] = &[
+    "--follow",
+    "--lines=0",
+    "--output=cat",
+    "_COMM=earlyoom",
+    "_COMM=systemd-oomd",
+];
+
+// how often a log file is checked for new lines once its end is reached
+const LOG_FILE_POLL_INTERVAL: time::Duration = time::Duration::from_millis(1000);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserspaceKill {
+    pub killer: &'static str,
+    pub pid: Option<i32>,
+    pub uid: Option<u32>,
+    pub comm: Option<String>,
+    pub signal: Option<String>,
+    pub score: Option<i32>,
+    pub rss_kb: Option<u64>,
+    // systemd-oomd kills whole cgroups
+    pub cgroup: Option<String>,
+    pub reason: Option<String>,
+}
+
+impl UserspaceKill {
+    pub fn scope(&self) -> incident::OomScope {
+        match self.cgroup {
+            Some(_) => incident::OomScope::Memcg,
+            None => incident::OomScope::Global,
+        }
+    }
+}
+
+pub struct Detector {
+    pub processes: Arc<Mutex<LruCache<i32, process::ProcessInfo>>>,
+    pub sink: pipeline::Sink,
+    earlyoom: Regex,
+    systemd_oomd: Regex,
+    // earlyoom escalates to SIGKILL if SIGTERM is not enough, the victim is reported once
+    last_pid: Option<i32>,
+}
+
+impl Detector {
+    pub fn new(
+        processes: Arc<Mutex<LruCache<i32, process::ProcessInfo>>>,
+        sink: pipeline::Sink,
+    ) -> Detector {
+        Detector {
+            processes,
+            sink,
+            earlyoom: Regex::new(EARLYOOM_KILL).unwrap(), // this is a valid regex
+            systemd_oomd: Regex::new(SYSTEMD_OOMD_KILL).unwrap(), // this is a valid regex
+            last_pid: None,
+        }
+    }
+
+    pub fn parse_line(&self, line: &str) -> Option<UserspaceKill> {
+        if let Some(captures) = self.earlyoom.captures(line) {
+            return Some(UserspaceKill {
+                killer: "earlyoom",
+                pid: captures["pid"].parse::<i32>().ok(),
+                uid: captures
+                    .name("uid")
+                    .and_then(|uid| uid.as_str().parse::<u32>().ok()),
+                comm: Some(captures["comm"].to_string()),
+                signal: Some(captures["signal"].to_string()),
+                score: captures["score"].parse::<i32>().ok(),
+                rss_kb: captures["rss_mib"]
+                    .parse::<u64>()
+                    .ok()
+                    .map(|mib| mib * 1024),
+                cgroup: None,
+                reason: None,
+            });
+        }
+
+        let captures = self.systemd_oomd.captures(line)?;
+        Some(UserspaceKill {
+            killer: "systemd-oomd",
+            pid: None,
+            uid: None,
+            comm: None,
+            signal: None,
+            score: None,
+            rss_kb: None,
+            cgroup: Some(captures["cgroup"].to_string()),
+            reason: Some(captures["reason"].to_string()),
+        })
+    }
+
+    pub fn process_line(&mut self, line: &str) {
+        let kill = match self.parse_line(line) {
+            Some(kill) => kill,
+            None => return,
+        };
+
+        if kill.pid.is_some() && kill.pid == self.last_pid {
+            return;
+        }
+        self.last_pid = kill.pid;
+
+        let scope = kill.scope().as_str();
+        if !self.sink.in_scope(scope) {
+            info!(
+                "Ignoring the kill of {} since its scope {} is filtered out",
+                kill.killer, scope
+            );
+            return;
+        }
+
+        // systemd-oomd does not log the pids, the members of the killed cgroup are its victims
+        let (victims, ancestors) = match self.processes.lock() {
+            Ok(mut procs) => {
+                let victims = match (kill.pid, kill.cgroup.as_deref()) {
+                    (Some(pid), _) => procs.pop(&pid).map(|p| (pid, p)).into_iter().collect(),
+                    (None, Some(cgroup)) => process::cgroup_members(&procs, cgroup),
+                    (None, None) => Vec::new(),
+                };
+                for (pid, _) in &victims {
+                    procs.pop(pid);
+                }
+                let ancestors = match victims.first() {
+                    Some((_, process)) => process::ancestry(&procs, process),
+                    None => Vec::new(),
+                };
+                (victims, ancestors)
+            }
+            Err(e) => {
+                error!("Could not acquire the process table lock in the userspace-killers thread!. Error: {}", e);
+                (Vec::new(), Vec::new())
+            }
+        };
+        if victims.is_empty() {
+            warn!(
+                "Detected a kill by {} for pid {:?} (cgroup {:?}) but could not obtain informations about the processes",
+                kill.killer, kill.pid, kill.cgroup
+            );
+        }
+
+        let kill_event =
+            events::build_userspace_kill_event(&kill, victims, events::build_ancestors(ancestors));
+        info!("New OOM event: {}", &kill_event);
+        self.sink.emit(&kill_event);
+    }
+
+    /// Follow the journal entries of the userspace killers until journalctl exits
+    pub fn follow_journal(&mut self) -> Result<(), String> {
+        let mut journalctl = Command::new("journalctl");
+        journalctl
+            .args(JOURNALCTL_ARGS)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        // journalctl must not outlive the daemon
+        unsafe {
+            journalctl.pre_exec(|| {
+                libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+                Ok(())
+            });
+        }
+
+        let mut child = match journalctl.spawn() {
+            Ok(child) => child,
+            Err(e) => return Err(format!("Could not run journalctl: {}", e)),
+        };
+        let stdout = match child.stdout.take() {
+            Some(stdout) => stdout,
+            None => return Err("Could not read the output of journalctl".to_string()),
+        };
+
+        for line in BufReader::new(stdout).lines() {
+            match line {
+                Ok(line) => self.process_line(&line),
+                Err(e) => return Err(format!("Could not read the output of journalctl: {}", e)),
+            }
+        }
+
+        Err(format!("journalctl exited: {:?}", child.wait()))
+    }
+
+    /// Follow a log file from its end, it is reopened when rotated and read again when truncated
+    pub fn follow_file(&mut self, path: &str, term: &AtomicBool) {
+        let mut reader: Option<(BufReader<File>, u64)> = None;
+        let mut line = String::new();
+        // only the file found at startup has old lines, the ones created afterwards are read from their start
+        let mut skip_existing = true;
+
+        while !term.load(Ordering::Relaxed) {
+            let (file_reader, inode) = match reader.as_mut() {
+                Some(reader) => reader,
+                None => match File::open(path).and_then(|mut file| {
+                    if skip_existing {
+                        file.seek(SeekFrom::End(0))?;
+                    }
+                    let inode = file.metadata()?.ino();
+                    Ok((BufReader::new(file), inode))
+                }) {
+                    Ok(opened) => {
+                        skip_existing = false;
+                        reader.insert(opened)
+                    }
+                    Err(e) => {
+                        skip_existing = false;
+                        debug!("Could not open the log file {}: {}", path, e);
+                        std::thread::sleep(LOG_FILE_POLL_INTERVAL);
+                        continue;
+                    }
+                },
+            };
+
+            line.clear();
+            match file_reader.read_line(&mut line) {
+                // a partial line is completed by the next read
+                Ok(size) if size > 0 && line.ends_with('\n') => {
+                    self.process_line(line.trim_end());
+                    continue;
+                }
+                Ok(size) if size > 0 => {
+                    let _ = file_reader.seek(SeekFrom::Current(-(size as i64)));
+                }
+                Ok(_) => {}
+                Err(e) => error!("Could not read the log file {}: {}", path, e),
+            }
+
+            // at the end of the file, a rotated one is replaced by a new file with another inode
+            // or truncated in place (logrotate copytruncate), both are read from their start
+            match std::fs::metadata(path) {
+                Ok(metadata) if metadata.ino() == *inode => {
+                    let truncated = file_reader
+                        .stream_position()
+                        .is_ok_and(|position| metadata.len() < position);
+                    if truncated {
+                        debug!(
+                            "The log file {} was truncated, reading it again from its start",
+                            path
+                        );
+                        if let Err(e) = file_reader.seek(SeekFrom::Start(0)) {
+                            error!("Could not read the log file {}: {}", path, e);
+                            reader = None;
+                        }
+                    }
+                }
+                _ => reader = None,
+            }
+            std::thread::sleep(LOG_FILE_POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::notifiers;
+
+    fn detector() -> Detector {
+        Detector::new(
+            Arc::new(Mutex::new(LruCache::new(16))),
+            pipeline::Sink {
+                notifiers: notifiers::Notifiers::default(),
+                oom_scopes: Vec::new(),
+                kubernetes: None,
+            },
+        )
+    }
+
+    fn earlyoom_kill(signal: &str, uid: Option<u32>) -> UserspaceKill {
+        UserspaceKill {
+            killer: "earlyoom",
+            pid: Some(2876),
+            uid,
+            comm: Some("java".to_string()),
+            signal: Some(signal.to_string()),
+            score: Some(910),
+            rss_kb: Some(7412 * 1024),
+            cgroup: None,
+            reason: None,
+        }
+    }
+
+    #[test]
+    fn userspace_kills() {
+        let cases = [
+            // earlyoom 1.6
+            (
+                r#"sending SIGTERM to process 2876 uid 1000 "java": badness 910, VmRSS 7412 MiB"#,
+                Some(earlyoom_kill("SIGTERM", Some(1000))),
+            ),
+            // earlyoom 1.7+
+            (
+                r#"sending SIGKILL to process 2876 uid 1000 "java": oom_score 910, VmRSS 7412 MiB, cmdline "/usr/bin/java -jar app.jar""#,
+                Some(earlyoom_kill("SIGKILL", Some(1000))),
+            ),
+            // earlyoom before 1.6 does not log the uid
+            (
+                r#"sending SIGTERM to process 2876 "java": badness 910, VmRSS 7412 MiB"#,
+                Some(earlyoom_kill("SIGTERM", None)),
+            ),
+            (
+                "Killed /system.slice/batch.service due to memory pressure for /system.slice being 62.13% > 50.00% for > 20s with reclaim activity",
+                Some(UserspaceKill {
+                    killer: "systemd-oomd",
+                    pid: None,
+                    uid: None,
+                    comm: None,
+                    signal: None,
+                    score: None,
+                    rss_kb: None,
+                    cgroup: Some("/system.slice/batch.service".to_string()),
+                    reason: Some(
+                        "memory pressure for /system.slice being 62.13% > 50.00% for > 20s with reclaim activity"
+                            .to_string(),
+                    ),
+                }),
+            ),
+            ("earlyoom v1.7", None),
+            (
+                "mem avail:    21 of  7859 MiB ( 0.27%), swap free:    0 of    0 MiB ( 0.00%)",
+                None,
+            ),
+            ("low memory! at or below SIGTERM limits: mem 10.00%, swap 5.00%", None),
+            (
+                "Killed process 2876 (java) total-vm:7593220kB, anon-rss:7589632kB, file-rss:0kB, shmem-rss:0kB",
+                None,
+            ),
+        ];
+
+        let detector = detector();
+        for (i, (line, kill)) in cases.iter().enumerate() {
+            assert_eq!(&detector.parse_line(line), kill, "case {}", i);
+        }
+    }
+}
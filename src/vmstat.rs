@@ -0,0 +1,125 @@
+use std::fs;
+use std::time;
+
+/*
+    The kernel counts every oom kill in /proc/vmstat (kernel 4.13+):
+    oom_kill 3
+    Comparing it with the kills found in the kernel log tells if some of them were missed.
+*/
+
+const VMSTAT_PATH: &str = "/proc/vmstat";
+
+// the kills are counted before being logged, so the counter is compared with the kills detected an interval later
+const CHECK_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+pub fn read_oom_kills() -> Result<u64, String> {
+    let content = match fs::read_to_string(VMSTAT_PATH) {
+        Ok(content) => content,
+        Err(e) => return Err(format!("Could not read {}: {}", VMSTAT_PATH, e)),
+    };
+
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .ok_or(format!("{} has no oom_kill counter", VMSTAT_PATH))
+}
+
+pub struct KillCrossCheck {
+    // the value of the counter when the kernel log started being read
+    baseline: u64,
+    last_sample: Option<u64>,
+    last_check: time::Instant,
+    reported_missed: u64,
+}
+
+impl KillCrossCheck {
+    /// The kills counted since the baseline are expected (0 when the whole kernel log is read, the saved counter
+    /// when it is resumed), otherwise only the ones counted after the sample taken at startup
+    pub fn new(baseline: Option<u64>, sample: u64) -> KillCrossCheck {
+        KillCrossCheck {
+            baseline: baseline.unwrap_or(sample),
+            last_sample: None,
+            last_check: time::Instant::now(),
+            reported_missed: 0,
+        }
+    }
+
+    pub fn baseline(&self) -> u64 {
+        self.baseline
+    }
+
+    /// Sample the counter once per interval and check it against the kills handled
+    pub fn poll(&mut self, handled: u64) -> Option<(u64, u64)> {
+        if self.last_check.elapsed() < CHECK_INTERVAL {
+            return None;
+        }
+        self.last_check = time::Instant::now();
+
+        match read_oom_kills() {
+            Ok(sample) => self.check(sample, handled),
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        }
+    }
+
+    /// The kills counted by the kernel and not handled, returned once every time they grow
+    pub fn check(&mut self, sample: u64, handled: u64) -> Option<(u64, u64)> {
+        let expected = self
+            .last_sample
+            .replace(sample)?
+            .saturating_sub(self.baseline);
+        let missed = expected.saturating_sub(handled);
+        if missed <= self.reported_missed {
+            return None;
+        }
+
+        self.reported_missed = missed;
+        Some((expected, missed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baseline_at_startup() {
+        let mut cross_check = KillCrossCheck::new(None, 5);
+        assert_eq!(cross_check.baseline(), 5);
+
+        // the first sample is only compared at the next check, once the kills it counts are logged
+        assert_eq!(cross_check.check(6, 0), None);
+        assert_eq!(cross_check.check(6, 1), None);
+        assert_eq!(cross_check.check(6, 1), None);
+    }
+
+    #[test]
+    fn resumed_from_saved_counter() {
+        // two kills were counted while the daemon was down, both are read again from the kernel log
+        let mut cross_check = KillCrossCheck::new(Some(3), 5);
+        assert_eq!(cross_check.baseline(), 3);
+        assert_eq!(cross_check.check(5, 0), None);
+        assert_eq!(cross_check.check(5, 2), None);
+
+        // only one of them was read again
+        let mut cross_check = KillCrossCheck::new(Some(3), 5);
+        assert_eq!(cross_check.check(5, 0), None);
+        assert_eq!(cross_check.check(5, 1), Some((2, 1)));
+    }
+
+    #[test]
+    fn missed_reported_once_per_increase() {
+        let mut cross_check = KillCrossCheck::new(None, 0);
+        assert_eq!(cross_check.check(1, 0), None);
+        assert_eq!(cross_check.check(1, 0), Some((1, 1)));
+        assert_eq!(cross_check.check(1, 0), None);
+        assert_eq!(cross_check.check(3, 0), None);
+        assert_eq!(cross_check.check(3, 0), Some((3, 3)));
+        assert_eq!(cross_check.check(3, 0), None);
+        // a late kill line lowers the missed kills, they are not reported again
+        assert_eq!(cross_check.check(3, 1), None);
+    }
+}